@@ -0,0 +1,98 @@
+//! Burst playback benchmark: starting many voices of the same sound in one frame.
+//!
+//! Runs `SoundSystem::play_sound` on the null backend, which reads the voices forward on each
+//! update like the output stream would, so the time covers starting the voices and playing one
+//! audio callback of them. The first burst also decodes the sound, the next ones reuse it.
+//! The baseline is the old per voice path: a thread per voice that copies the cached bytes and
+//! decodes them.
+//!
+//! Run with `cargo bench --bench audio_voices`.
+
+use mi_motor::audio_backend::NullBackend;
+use mi_motor::sound_system::{PlayOptions, SoundSystem};
+use rodio::Decoder;
+use std::io::{BufReader, Cursor};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SOUND: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/audio/blaster.wav");
+const VOICES: usize = 256;
+// One callback of 1024 samples at 44.1 kHz
+const SAMPLES_PER_CALLBACK: usize = 1024;
+const CALLBACK_SECONDS: f32 = SAMPLES_PER_CALLBACK as f32 / 44_100.0;
+const ROUNDS: usize = 10;
+
+fn sound_system() -> SoundSystem {
+    let sounds = SoundSystem::with_backend(Box::new(NullBackend::new()));
+    sounds.set_max_voices(VOICES).unwrap();
+    sounds
+}
+
+/// Starts every voice, plays one callback of them and then stops them to free the pool.
+fn burst(sounds: &SoundSystem) -> Duration {
+    let options = PlayOptions::default();
+    let start = Instant::now();
+    let handles: Vec<_> = (0..VOICES)
+        .map(|_| sounds.play_sound(SOUND, &options).unwrap())
+        .collect();
+    sounds.update(CALLBACK_SECONDS, &[]).unwrap();
+    let elapsed = start.elapsed();
+
+    for handle in &handles {
+        handle.stop();
+    }
+    sounds.update(CALLBACK_SECONDS, &[]).unwrap();
+    elapsed
+}
+
+/// The voices as they were started before the decoded sounds were shared.
+fn legacy_burst(bytes: &Arc<Vec<u8>>) -> Duration {
+    let start = Instant::now();
+    let threads: Vec<_> = (0..VOICES)
+        .map(|_| {
+            let bytes = bytes.clone();
+            thread::spawn(move || {
+                let cursor = Cursor::new(bytes.as_ref().to_owned());
+                let decoder = Decoder::new(BufReader::new(cursor)).unwrap();
+                decoder.take(SAMPLES_PER_CALLBACK).count()
+            })
+        })
+        .collect();
+    for handle in threads {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, per_burst: Duration) {
+    println!(
+        "{:<28} {:>10.3} ms per burst of {} voices",
+        name,
+        per_burst.as_secs_f64() * 1000.0,
+        VOICES
+    );
+}
+
+fn main() {
+    let bytes = Arc::new(std::fs::read(SOUND).expect("Could not read the benchmark sound"));
+    legacy_burst(&bytes);
+    let legacy = (0..ROUNDS).map(|_| legacy_burst(&bytes)).sum::<Duration>() / ROUNDS as u32;
+    report("thread + copy + decode", legacy);
+
+    let first = (0..ROUNDS)
+        .map(|_| burst(&sound_system()))
+        .sum::<Duration>()
+        / ROUNDS as u32;
+    report("first burst (decodes)", first);
+
+    let sounds = sound_system();
+    burst(&sounds);
+    let cached = (0..ROUNDS).map(|_| burst(&sounds)).sum::<Duration>() / ROUNDS as u32;
+    report("later bursts (cached)", cached);
+    println!(
+        "speedup: {:.1}x first burst, {:.1}x cached",
+        legacy.as_secs_f64() / first.as_secs_f64().max(f64::EPSILON),
+        legacy.as_secs_f64() / cached.as_secs_f64().max(f64::EPSILON)
+    );
+}
@@ -0,0 +1,223 @@
+//! Spatial index benchmark: collision pairs, overlap queries and raycasts over thousands of
+//! objects.
+//!
+//! Compares testing every object, and every pair for the broad phase, with the uniform grid
+//! of `SpatialGrid`. The answers of both are checked to be the same before timing them.
+//!
+//! Run with `cargo bench --bench spatial_index`.
+
+extern crate nalgebra as na;
+
+use mi_motor::collision::{broad_phase, gjk, Bounds, ColliderKind, WorldShape};
+use mi_motor::object3d::Object3d;
+use mi_motor::query::{self, QueryFilter, RayTarget};
+use mi_motor::spatial::SpatialGrid;
+use na::Vector3;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::time::{Duration, Instant};
+
+const MODEL: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/model/cube.obj");
+const OBJECT_COUNTS: [usize; 3] = [1000, 4000, 16000];
+// Space per object, so the crowding is the same for every count
+const SPACE_PER_OBJECT: f32 = 60.0;
+const CELL_SIZE: f32 = 4.0;
+const QUERIES: usize = 200;
+const ROUNDS: usize = 5;
+
+fn scene(count: usize, rng: &mut StdRng) -> Vec<Object3d> {
+    let side = (count as f32 * SPACE_PER_OBJECT).cbrt();
+    let mut template = Object3d::new(0, MODEL, Vector3::zeros(), 0.0);
+    template.set_collider(Some(ColliderKind::Obb));
+    (0..count)
+        .map(|i| {
+            let mut obj = template.clone();
+            obj.random_id = i as u32;
+            obj.position = Vector3::new(
+                rng.gen_range(0.0..side),
+                rng.gen_range(0.0..side),
+                rng.gen_range(0.0..side),
+            );
+            obj.rotation = rng.gen_range(0.0..std::f32::consts::TAU);
+            obj
+        })
+        .collect()
+}
+
+fn linear_pairs(objects: &[Object3d]) -> Vec<(usize, usize)> {
+    let bounds: Vec<Bounds> = objects.iter().map(Object3d::bounds).collect();
+    let mut pairs = Vec::new();
+    for i in 0..bounds.len() {
+        for j in i + 1..bounds.len() {
+            if bounds[i].overlaps(&bounds[j]) {
+                pairs.push((i, j));
+            }
+        }
+    }
+    pairs
+}
+
+fn sweep_pairs(objects: &[Object3d]) -> Vec<(usize, usize)> {
+    let bounds: Vec<Bounds> = objects.iter().map(Object3d::bounds).collect();
+    let mut pairs = broad_phase(&bounds);
+    pairs.sort_unstable();
+    pairs
+}
+
+fn linear_overlap(objects: &[Object3d], center: &Vector3<f32>, radius: f32) -> Vec<u32> {
+    let sphere = WorldShape::Sphere {
+        center: *center,
+        radius,
+    };
+    objects
+        .iter()
+        .filter(|obj| {
+            obj.collision_shape()
+                .is_some_and(|shape| gjk(&shape, &sphere).is_some())
+        })
+        .map(|obj| obj.random_id)
+        .collect()
+}
+
+fn linear_raycast(
+    objects: &[Object3d],
+    origin: &Vector3<f32>,
+    dir: &Vector3<f32>,
+    max_distance: f32,
+) -> Option<u32> {
+    let mut closest: Option<(f32, u32)> = None;
+    for obj in objects {
+        let limit = closest.map_or(max_distance, |(distance, _)| distance);
+        let shape = obj.collision_shape().unwrap();
+        if let Some((distance, _)) = query::ray_shape(&shape, origin, dir, limit) {
+            closest = Some((distance, obj.random_id));
+        }
+    }
+    closest.map(|(_, id)| id)
+}
+
+fn measure(name: &str, mut run: impl FnMut()) -> Duration {
+    run();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        run();
+    }
+    let per_run = start.elapsed() / ROUNDS as u32;
+    println!("  {:<36} {:>10.3} ms", name, per_run.as_secs_f64() * 1000.0);
+    per_run
+}
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(7);
+    for count in OBJECT_COUNTS {
+        let mut objects = scene(count, &mut rng);
+        let side = (count as f32 * SPACE_PER_OBJECT).cbrt();
+        let mut grid = SpatialGrid::new(CELL_SIZE);
+        grid.sync(&objects);
+        println!("{} objects, {} in the grid", count, grid.len());
+
+        let spheres: Vec<(Vector3<f32>, f32)> = (0..QUERIES)
+            .map(|_| {
+                let center = Vector3::new(
+                    rng.gen_range(0.0..side),
+                    rng.gen_range(0.0..side),
+                    rng.gen_range(0.0..side),
+                );
+                (center, rng.gen_range(1.0..5.0))
+            })
+            .collect();
+        let rays: Vec<(Vector3<f32>, Vector3<f32>)> = (0..QUERIES)
+            .map(|_| {
+                let origin = Vector3::new(
+                    rng.gen_range(0.0..side),
+                    rng.gen_range(0.0..side),
+                    rng.gen_range(0.0..side),
+                );
+                let dir = Vector3::new(
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                )
+                .normalize();
+                (origin, dir)
+            })
+            .collect();
+        let filter = QueryFilter::default();
+
+        let pairs = linear_pairs(&objects);
+        assert_eq!(pairs, sweep_pairs(&objects), "sweep and prune pairs differ");
+        assert_eq!(pairs, grid.pairs(), "grid pairs differ");
+        for (center, radius) in &spheres {
+            let mut expected = linear_overlap(&objects, center, *radius);
+            let mut found = query::overlap_sphere(&objects, &grid, center, *radius, &filter);
+            expected.sort_unstable();
+            found.sort_unstable();
+            assert_eq!(expected, found, "overlap results differ");
+        }
+        for (origin, dir) in &rays {
+            let expected = linear_raycast(&objects, origin, dir, side);
+            let found = query::raycast(
+                &objects,
+                &grid,
+                origin,
+                dir,
+                side,
+                RayTarget::Colliders,
+                &filter,
+            );
+            assert_eq!(expected, found.map(|hit| hit.id), "raycast results differ");
+        }
+
+        println!(" broad phase, {} pairs", pairs.len());
+        if count <= 4000 {
+            measure("every pair", || {
+                linear_pairs(&objects);
+            });
+        }
+        measure("sweep and prune", || {
+            sweep_pairs(&objects);
+        });
+        measure("grid", || {
+            grid.pairs();
+        });
+
+        println!(" keeping the grid in sync");
+        measure("move every object and sync", || {
+            for obj in objects.iter_mut() {
+                obj.position.x += 0.1;
+            }
+            grid.sync(&objects);
+        });
+
+        println!(" {} sphere overlaps", QUERIES);
+        measure("every object", || {
+            for (center, radius) in &spheres {
+                linear_overlap(&objects, center, *radius);
+            }
+        });
+        measure("grid", || {
+            for (center, radius) in &spheres {
+                query::overlap_sphere(&objects, &grid, center, *radius, &filter);
+            }
+        });
+
+        println!(" {} raycasts", QUERIES);
+        measure("every object", || {
+            for (origin, dir) in &rays {
+                linear_raycast(&objects, origin, dir, side);
+            }
+        });
+        measure("grid", || {
+            for (origin, dir) in &rays {
+                query::raycast(
+                    &objects,
+                    &grid,
+                    origin,
+                    dir,
+                    side,
+                    RayTarget::Colliders,
+                    &filter,
+                );
+            }
+        });
+    }
+}
@@ -4,7 +4,8 @@ function start()
             y = 0,
             z = 5
         },
-        0.0)
+        0.0,
+        { collider = 'hull' })
 
     print("Script started successfully!")
 end
@@ -1,7 +1,4 @@
 local function update_cube(dt, object, inputs)
-    SPEED = 15.0
-    object.position.z = object.position.z + (SPEED * dt)
-
     if object.position.z > 40.0 then
         object.render = false
     end
@@ -13,27 +10,37 @@ TimesShoot = 0
 local function update_aircraft(dt, object, inputs, utils)
     SPEED = 10.0
 
+    local motion = { x = 0, y = 0, z = 0 }
     if utils.has_value(inputs.pressing, 'A') then
-        object.position.x = object.position.x + (SPEED * dt)
+        motion.x = motion.x + (SPEED * dt)
     end
     if utils.has_value(inputs.pressing, 'D') then
-        object.position.x = object.position.x - (SPEED * dt)
+        motion.x = motion.x - (SPEED * dt)
     end
     if utils.has_value(inputs.pressing, 'W') then
-        object.position.y = object.position.y - (SPEED * dt)
+        motion.y = motion.y - (SPEED * dt)
     end
     if utils.has_value(inputs.pressing, 'S') then
-        object.position.y = object.position.y + (SPEED * dt)
+        motion.y = motion.y + (SPEED * dt)
     end
+    -- Moves the ship without going through other colliders
+    move_and_slide(object, motion)
 
     if utils.has_value(inputs.just_pressed, 'P') then
-        play_sound('audio/blaster.wav')
+        play_sound('audio/blaster.wav', { object = object.id, min_distance = 5 })
         new_model('model/cube.obj', {
                 x = object.position.x,
                 y = object.position.y,
                 z = object.position.z
             },
-            object.rotation)
+            object.rotation,
+            {
+                collider = 'aabb',
+                -- The ship is on layer 1, projectiles only hit what is on layer 3
+                layer = 2,
+                mask = { 3 },
+                body = { gravity_scale = 0, velocity = { x = 0, y = 0, z = 15 } }
+            })
         TimesShoot = TimesShoot + 1
         print('TimesShoot: ' .. TimesShoot)
     end
@@ -41,6 +48,14 @@ local function update_aircraft(dt, object, inputs, utils)
     return object
 end
 
+-- Projectiles disappear when they hit something, their mask already skips the ship
+function on_collision_enter(object, other)
+    if object.name == 'model/cube.obj' then
+        object.render = false
+    end
+    return object
+end
+
 function update(dt, object, inputs)
     local utils = require('scripting/utils')
 
@@ -0,0 +1,157 @@
+use rodio::queue::SourcesQueueOutput;
+use rodio::{OutputStream, OutputStreamHandle, Sink, Source};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+
+use crate::sound_system::SoundError;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlayKind {
+    Sound,
+    Music,
+    // Synthesized with play_tone, the path is "tone:<wave>:<freq>"
+    Tone,
+}
+
+/// What the scripts asked to play, as seen by the backend.
+#[derive(Clone, Debug)]
+pub struct PlayedSound {
+    pub kind: PlayKind,
+    pub path: String,
+    pub bus: String,
+    pub volume: f32,
+    pub looping: bool,
+    pub positional: bool,
+}
+
+/// Where the sound system sends its audio.
+pub trait AudioBackend {
+    fn name(&self) -> &'static str;
+
+    /// Creates a sink connected to the output, or `None` when the backend does not output audio.
+    fn new_sink(&self) -> Result<Option<Sink>, SoundError>;
+
+    /// Called for every sound and music track that starts playing.
+    fn record(&self, _played: &PlayedSound) {}
+
+    /// Called once per frame with its length in seconds. Devices play on their own, backends
+    /// without one move their sinks forward here.
+    fn advance(&self, _dt: f32) {}
+}
+
+/// Plays through the default audio device.
+pub struct RodioBackend {
+    _stream: OutputStream,
+    stream_handle: OutputStreamHandle,
+}
+
+impl RodioBackend {
+    pub fn new() -> Result<Self, SoundError> {
+        let (stream, stream_handle) = OutputStream::try_default()?;
+        Ok(RodioBackend {
+            _stream: stream,
+            stream_handle,
+        })
+    }
+}
+
+impl AudioBackend for RodioBackend {
+    fn name(&self) -> &'static str {
+        "rodio"
+    }
+
+    fn new_sink(&self) -> Result<Option<Sink>, SoundError> {
+        Ok(Some(Sink::try_new(&self.stream_handle)?))
+    }
+}
+
+pub type PlayLog = Arc<Mutex<Vec<PlayedSound>>>;
+
+/// Outputs nothing and keeps a log of what was played, for tests, CI and servers.
+/// Its sinks are read on `advance` at the speed a device would play them, so sounds finish,
+/// stop and free their voices like they do with audio.
+#[derive(Default)]
+pub struct NullBackend {
+    log: PlayLog,
+    // What the sinks handed out play, read instead of being sent to a device
+    outputs: Mutex<Vec<SourcesQueueOutput<f32>>>,
+}
+
+impl NullBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The log of played sounds, it can be read while the backend is owned by the sound system.
+    pub fn log(&self) -> PlayLog {
+        self.log.clone()
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn name(&self) -> &'static str {
+        "null"
+    }
+
+    fn new_sink(&self) -> Result<Option<Sink>, SoundError> {
+        let (sink, output) = Sink::new_idle();
+        self.outputs
+            .lock()
+            .map_err(|_| "The outputs of the null backend are poisoned")?
+            .push(output);
+        Ok(Some(sink))
+    }
+
+    fn record(&self, played: &PlayedSound) {
+        if let Ok(mut log) = self.log.lock() {
+            log.push(played.clone());
+        }
+    }
+
+    fn advance(&self, dt: f32) {
+        let Ok(mut outputs) = self.outputs.lock() else {
+            return;
+        };
+        outputs.retain_mut(|output| {
+            let samples = (dt * output.sample_rate() as f32) as usize * output.channels() as usize;
+            // An output ends once its sink is dropped and what it had queued is played
+            (0..samples.max(1)).all(|_| output.next().is_some())
+        });
+    }
+}
+
+/// Which backend to use, `Auto` falls back to the null backend when there is no audio device.
+/// It is the only fallback, asking for `Rodio` without a device is an error.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Auto,
+    Rodio,
+    Null,
+}
+
+impl BackendKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(BackendKind::Auto),
+            "rodio" => Some(BackendKind::Rodio),
+            "null" => Some(BackendKind::Null),
+            _ => None,
+        }
+    }
+
+    pub fn create(self) -> Result<Box<dyn AudioBackend>, SoundError> {
+        match self {
+            BackendKind::Rodio => Ok(Box::new(RodioBackend::new()?)),
+            BackendKind::Null => Ok(Box::new(NullBackend::new())),
+            BackendKind::Auto => match RodioBackend::new() {
+                Ok(backend) => Ok(Box::new(backend)),
+                Err(e) => {
+                    eprintln!("Error iniciando el audio, se usara el backend nulo: {}", e);
+                    Ok(Box::new(NullBackend::new()))
+                }
+            },
+        }
+    }
+}
@@ -1,6 +1,8 @@
 extern crate nalgebra as na;
 use na::{Matrix4, Vector3};
 
+use crate::collision::Bounds;
+
 pub struct Camera3d {
     // Field of view on radians
     fov: f32,
@@ -8,6 +10,8 @@ pub struct Camera3d {
     aspect: f32,
     near: f32,
     far: f32,
+    // World position, it is also where the sounds are heard from
+    pub position: Vector3<f32>,
 }
 
 impl Camera3d {
@@ -18,9 +22,20 @@ impl Camera3d {
             aspect,
             near,
             far,
+            position: Vector3::zeros(),
         }
     }
 
+    // Width / Height of the screen, set by the renderer when its surface changes size
+    pub fn set_aspect(&mut self, aspect: f32) {
+        self.aspect = aspect;
+    }
+
+    // Direction of the right side of the screen, the projection mirrors the x axis
+    pub fn right(&self) -> Vector3<f32> {
+        -Vector3::x()
+    }
+
     // Returns the projection matrix
     pub fn projection_matrix(&self) -> Matrix4<f32> {
         let f = 1.0 / (self.fov / 2.0).tan();
@@ -44,6 +59,40 @@ impl Camera3d {
         )
     }
 
+    // The part of the world seen on a screen of that size, as drawn by the renderer: it puts
+    // the projected points 100 pixels per unit away from the center of the screen
+    pub fn frustum(&self, width: f32, height: f32) -> Frustum {
+        let f = 1.0 / (self.fov / 2.0).tan();
+        // Largest projected coordinates that still land on the screen
+        let half_x = width / 200.0;
+        let half_y = height / 200.0;
+        // Points in front of the camera have a positive z
+        let normals = [
+            Vector3::new(-f / self.aspect, 0.0, half_x),
+            Vector3::new(f / self.aspect, 0.0, half_x),
+            Vector3::new(0.0, -f, half_y),
+            Vector3::new(0.0, f, half_y),
+        ];
+        let mut planes = [(Vector3::zeros(), 0.0); 6];
+        for (plane, normal) in planes.iter_mut().zip(normals) {
+            let normal = normal.normalize();
+            *plane = (normal, -normal.dot(&self.position));
+        }
+        planes[4] = (Vector3::z(), -self.near - self.position.z);
+        planes[5] = (-Vector3::z(), self.far + self.position.z);
+        // The frustum is widest at the far plane
+        let half_extent = Vector3::new(
+            half_x * self.far * self.aspect / f,
+            half_y * self.far / f,
+            0.0,
+        );
+        let bounds = Bounds {
+            min: self.position - half_extent + Vector3::z() * self.near,
+            max: self.position + half_extent + Vector3::z() * self.far,
+        };
+        Frustum { planes, bounds }
+    }
+
     // Projects a 3D point into 2D screen coordinates
     pub fn project_point_with(
         &self,
@@ -59,3 +108,82 @@ impl Camera3d {
         Some((point4.x, point4.y))
     }
 }
+
+// Planes around the visible part of the world, their normals point inside
+pub struct Frustum {
+    planes: [(Vector3<f32>, f32); 6],
+    bounds: Bounds,
+}
+
+impl Frustum {
+    // Box around the frustum, to find what may be visible in the spatial index
+    pub fn bounds(&self) -> &Bounds {
+        &self.bounds
+    }
+
+    // False when the sphere is fully outside, it can be true for some spheres just outside
+    // a corner
+    pub fn contains_sphere(&self, center: &Vector3<f32>, radius: f32) -> bool {
+        self.planes
+            .iter()
+            .all(|(normal, distance)| normal.dot(center) + distance >= -radius)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frustum(position: Vector3<f32>) -> Frustum {
+        let mut camera = Camera3d::new(60.0, 4.0 / 3.0, 0.1, 1000.0);
+        camera.position = position;
+        camera.frustum(800.0, 600.0)
+    }
+
+    #[test]
+    fn spheres_in_front_are_kept_and_behind_are_culled() {
+        let frustum = frustum(Vector3::zeros());
+        assert!(frustum.contains_sphere(&Vector3::new(0.0, 0.0, 10.0), 1.0));
+        assert!(!frustum.contains_sphere(&Vector3::new(0.0, 0.0, -5.0), 1.0));
+        assert!(!frustum.contains_sphere(&Vector3::new(0.0, 0.0, 1001.5), 1.0));
+        assert!(!frustum.contains_sphere(&Vector3::new(50.0, 0.0, 10.0), 1.0));
+        assert!(!frustum.contains_sphere(&Vector3::new(0.0, -50.0, 10.0), 1.0));
+    }
+
+    #[test]
+    fn spheres_straddling_the_near_plane_are_kept() {
+        let frustum = frustum(Vector3::zeros());
+        // The center is behind the camera but the sphere reaches past the near plane
+        assert!(frustum.contains_sphere(&Vector3::new(0.0, 0.0, -0.3), 0.5));
+        assert!(!frustum.contains_sphere(&Vector3::new(0.0, 0.0, -0.5), 0.5));
+    }
+
+    #[test]
+    fn the_frustum_follows_the_camera() {
+        let frustum = frustum(Vector3::new(100.0, 0.0, -20.0));
+        assert!(frustum.contains_sphere(&Vector3::new(100.0, 0.0, -10.0), 1.0));
+        assert!(!frustum.contains_sphere(&Vector3::new(100.0, 0.0, -25.0), 1.0));
+        assert!(!frustum.contains_sphere(&Vector3::new(0.0, 0.0, -10.0), 1.0));
+    }
+
+    #[test]
+    fn the_box_of_the_frustum_holds_what_it_contains() {
+        let frustum = frustum(Vector3::new(3.0, -2.0, 5.0));
+        let bounds = frustum.bounds();
+        let point = |x: f32, y: f32, z: f32| Bounds {
+            min: Vector3::new(x, y, z),
+            max: Vector3::new(x, y, z),
+        };
+        for z in [0.2, 10.0, 500.0, 999.0] {
+            for (x, y) in [(-1.0, -1.0), (1.0, 1.0), (1.0, -1.0), (-1.0, 1.0)] {
+                // Just inside each corner of the screen at that depth
+                let inside =
+                    Vector3::new(x * z * 3.05, y * z * 1.71, z) + Vector3::new(3.0, -2.0, 5.0);
+                assert!(frustum.contains_sphere(&inside, 0.0));
+                assert!(bounds.overlaps(&point(inside.x, inside.y, inside.z)));
+            }
+        }
+        assert!(!bounds.overlaps(&point(3.0, -2.0, 4.0)));
+        assert!(!bounds.overlaps(&point(3.0, -2.0, 1006.0)));
+    }
+}
@@ -0,0 +1,53 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+/// Writes RGBA pixels, row by row from the top left corner, to a PNG file.
+pub fn save_png(
+    path: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Saves every frame to numbered images in a folder: frame_000000.png, frame_000001.png...
+pub struct FrameCapture {
+    dir: PathBuf,
+    next_frame: u32,
+    // The game runs at this fixed dt while capturing, whatever the real frame time is
+    pub dt: f32,
+}
+
+impl FrameCapture {
+    pub fn new(dir: &str, fps: f32) -> Result<Self, Box<dyn std::error::Error>> {
+        if !(fps.is_finite() && fps > 0.0) {
+            return Err(format!("The capture fps must be positive, got {}", fps).into());
+        }
+        std::fs::create_dir_all(dir)?;
+        Ok(FrameCapture {
+            dir: PathBuf::from(dir),
+            next_frame: 0,
+            dt: 1.0 / fps,
+        })
+    }
+
+    pub fn save(
+        &mut self,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self.dir.join(format!("frame_{:06}.png", self.next_frame));
+        self.next_frame += 1;
+        save_png(&path, width, height, pixels)
+    }
+}
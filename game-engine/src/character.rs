@@ -0,0 +1,448 @@
+use na::Vector3;
+
+use crate::collision::{epa, gjk, Bounds, WorldShape};
+use crate::object3d::Object3d;
+use crate::query::shape_cast;
+use crate::spatial::SpatialGrid;
+
+// Times the move can hit something and slide along it in one call
+const MAX_SLIDES: usize = 4;
+
+/// Moves an object with a collider without letting it pass through the other colliders, the
+/// way a player walks or flies: it slides along walls, climbs small steps and knows when it
+/// stands on the ground. Triggers and objects its filter does not collide with are ignored.
+#[derive(Clone, Debug)]
+pub struct CharacterController {
+    // Unit vector pointing up, against the gravity
+    pub up: Vector3<f32>,
+    // Highest step it climbs without jumping
+    pub step_offset: f32,
+    // Steepest slope it walks on, in radians; steeper ones block it like walls
+    pub slope_limit: f32,
+    // Gap kept between it and the surfaces, so it does not start the next move touching them
+    pub skin: f32,
+}
+
+impl Default for CharacterController {
+    fn default() -> Self {
+        CharacterController {
+            up: Vector3::new(0.0, -1.0, 0.0),
+            step_offset: 0.3,
+            slope_limit: 45f32.to_radians(),
+            skin: 0.01,
+        }
+    }
+}
+
+/// Where a move ended.
+#[derive(Clone, Debug)]
+pub struct CharacterMove {
+    pub position: Vector3<f32>,
+    // Standing on a walkable surface at the end of the move
+    pub grounded: bool,
+    // Ids of the objects it bumped into, each once
+    pub hits: Vec<u32>,
+}
+
+struct Hit {
+    distance: f32,
+    normal: Vector3<f32>,
+    id: u32,
+}
+
+impl CharacterController {
+    /// Moves the object at `index`, placed at `position` and `rotation`, by `motion`.
+    /// Objects without a collider just move. `grid` must be synced with `objects`.
+    pub fn move_and_slide(
+        &self,
+        objects: &[Object3d],
+        grid: &SpatialGrid,
+        index: usize,
+        position: Vector3<f32>,
+        rotation: f32,
+        motion: &Vector3<f32>,
+    ) -> CharacterMove {
+        let mut result = CharacterMove {
+            position,
+            grounded: false,
+            hits: Vec::new(),
+        };
+        let Some(collider) = &objects[index].collider else {
+            result.position += motion;
+            return result;
+        };
+        let rotation_matrix = Object3d::yaw_matrix(rotation);
+        let shape_at = |position: &Vector3<f32>| collider.world_shape(position, &rotation_matrix);
+
+        self.depenetrate(objects, grid, index, &shape_at, &mut result);
+
+        let mut remaining = *motion;
+        for _ in 0..MAX_SLIDES {
+            let Some(dir) = remaining.try_normalize(f32::EPSILON) else {
+                break;
+            };
+            let distance = remaining.norm();
+            let shape = shape_at(&result.position);
+            let Some(hit) = self.cast(objects, grid, index, &shape, &dir, distance) else {
+                result.position += remaining;
+                break;
+            };
+            if !result.hits.contains(&hit.id) {
+                result.hits.push(hit.id);
+            }
+            let travel = (hit.distance - self.skin).max(0.0);
+            result.position += dir * travel;
+            remaining = dir * (distance - travel);
+
+            if self.is_walkable(&hit.normal) {
+                result.grounded = true;
+            } else if let Some((stepped, left)) = self.try_step(
+                objects,
+                grid,
+                index,
+                &shape_at,
+                &result.position,
+                &remaining,
+            ) {
+                result.position = stepped;
+                result.grounded = true;
+                remaining = left;
+                continue;
+            }
+            remaining = self.slide(&remaining, &hit.normal);
+        }
+
+        // Standing still or moving sideways does not touch the ground, so look just below
+        if !result.grounded {
+            let shape = shape_at(&result.position);
+            result.grounded = self
+                .cast(objects, grid, index, &shape, &-self.up, self.skin * 2.0)
+                .is_some_and(|hit| self.is_walkable(&hit.normal));
+        }
+        result
+    }
+
+    fn is_walkable(&self, normal: &Vector3<f32>) -> bool {
+        normal.dot(&self.up) >= self.slope_limit.cos()
+    }
+
+    /// The rest of a move after hitting a surface, along it instead of into it. Walls and
+    /// steep slopes are not climbed by sliding up them.
+    fn slide(&self, remaining: &Vector3<f32>, normal: &Vector3<f32>) -> Vector3<f32> {
+        let slid = remaining - normal * remaining.dot(normal).min(0.0);
+        if self.is_walkable(normal) || slid.dot(&self.up) <= 0.0 {
+            return slid;
+        }
+        let wall = normal - self.up * normal.dot(&self.up);
+        match wall.try_normalize(f32::EPSILON) {
+            Some(wall) => remaining - wall * remaining.dot(&wall).min(0.0),
+            None => slid,
+        }
+    }
+
+    /// Climbs onto a step in front: up by the step offset, forward, then down onto walkable
+    /// ground. Returns where it ends and the part of the move left.
+    fn try_step(
+        &self,
+        objects: &[Object3d],
+        grid: &SpatialGrid,
+        index: usize,
+        shape_at: &impl Fn(&Vector3<f32>) -> WorldShape,
+        position: &Vector3<f32>,
+        remaining: &Vector3<f32>,
+    ) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        if self.step_offset <= 0.0 {
+            return None;
+        }
+        let forward = remaining - self.up * remaining.dot(&self.up);
+        let forward_distance = forward.norm();
+        let forward_dir = forward.try_normalize(f32::EPSILON)?;
+
+        let rise = match self.cast(
+            objects,
+            grid,
+            index,
+            &shape_at(position),
+            &self.up,
+            self.step_offset,
+        ) {
+            Some(hit) => (hit.distance - self.skin).max(0.0),
+            None => self.step_offset,
+        };
+        let raised = position + self.up * rise;
+
+        let advance = match self.cast(
+            objects,
+            grid,
+            index,
+            &shape_at(&raised),
+            &forward_dir,
+            forward_distance,
+        ) {
+            Some(hit) => (hit.distance - self.skin).max(0.0),
+            None => forward_distance,
+        };
+        if advance <= self.skin {
+            return None;
+        }
+        let ahead = raised + forward_dir * advance;
+
+        // Back down onto the step, it must be ground and not a ledge to fall from
+        let hit = self.cast(
+            objects,
+            grid,
+            index,
+            &shape_at(&ahead),
+            &-self.up,
+            rise + self.skin,
+        )?;
+        if !self.is_walkable(&hit.normal) {
+            return None;
+        }
+        let landed = ahead - self.up * (hit.distance - self.skin).max(0.0);
+        Some((landed, forward_dir * (forward_distance - advance)))
+    }
+
+    /// Pushes the character out of anything it starts inside, like an object that moved into it.
+    fn depenetrate(
+        &self,
+        objects: &[Object3d],
+        grid: &SpatialGrid,
+        index: usize,
+        shape_at: &impl Fn(&Vector3<f32>) -> WorldShape,
+        result: &mut CharacterMove,
+    ) {
+        let shape = shape_at(&result.position);
+        for (id, obstacle) in self.obstacles(objects, grid, index, &shape.bounds()) {
+            let shape = shape_at(&result.position);
+            let Some(simplex) = gjk(&shape, &obstacle) else {
+                continue;
+            };
+            if let Some(contact) = epa(&simplex, &shape, &obstacle) {
+                result.position -= contact.normal * (contact.depth + self.skin);
+                if !result.hits.contains(&id) {
+                    result.hits.push(id);
+                }
+            }
+        }
+    }
+
+    /// The first obstacle the shape touches moving `distance` along `dir`, stopping the skin
+    /// before it.
+    fn cast(
+        &self,
+        objects: &[Object3d],
+        grid: &SpatialGrid,
+        index: usize,
+        shape: &WorldShape,
+        dir: &Vector3<f32>,
+        distance: f32,
+    ) -> Option<Hit> {
+        let reach = distance + self.skin;
+        let start = shape.bounds();
+        let swept = start.union(&Bounds {
+            min: start.min + dir * reach,
+            max: start.max + dir * reach,
+        });
+        let mut closest: Option<Hit> = None;
+        for (id, obstacle) in self.obstacles(objects, grid, index, &swept) {
+            let limit = closest.as_ref().map_or(reach, |hit| hit.distance);
+            if let Some((distance, normal)) = shape_cast(shape, dir, limit, &obstacle) {
+                closest = Some(Hit {
+                    distance,
+                    normal,
+                    id,
+                });
+            }
+        }
+        closest
+    }
+
+    /// Ids and shapes of what the character at `index` can bump into inside `bounds`.
+    fn obstacles(
+        &self,
+        objects: &[Object3d],
+        grid: &SpatialGrid,
+        index: usize,
+        bounds: &Bounds,
+    ) -> Vec<(u32, WorldShape)> {
+        let filter = objects[index].filter;
+        grid.query_bounds(bounds)
+            .into_iter()
+            .filter(|&i| i != index)
+            .filter_map(|i| objects.get(i))
+            .filter(|obj| obj.render && !obj.trigger && obj.filter.collides_with(&filter))
+            .filter_map(|obj| Some((obj.random_id, obj.collision_shape()?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collision::Collider;
+    use crate::test_util::cube_at;
+    // Half the size of the character, a cube standing on the floor at y = 0 (up is -y)
+    const HALF: f32 = 0.5;
+
+    fn block(id: u32, center: Vector3<f32>, half_extents: Vector3<f32>) -> Object3d {
+        let mut obj = cube_at(id, center);
+        obj.collider = Some(Collider::Aabb {
+            center: Vector3::zeros(),
+            half_extents,
+        });
+        obj
+    }
+
+    /// The character first, standing on a wide floor, then the other blocks. The character has
+    /// the id 1 and the floor 2.
+    fn world(blocks: Vec<Object3d>) -> (Vec<Object3d>, SpatialGrid) {
+        let character = block(
+            1,
+            Vector3::new(0.0, -HALF - 0.01, 0.0),
+            Vector3::new(HALF, HALF, HALF),
+        );
+        let floor = block(
+            2,
+            Vector3::new(0.0, 0.5, 0.0),
+            Vector3::new(30.0, 0.5, 30.0),
+        );
+        let mut objects = vec![character, floor];
+        objects.extend(blocks);
+        let mut grid = SpatialGrid::new(4.0);
+        grid.sync(&objects);
+        (objects, grid)
+    }
+
+    fn walk(objects: &[Object3d], grid: &SpatialGrid, motion: Vector3<f32>) -> CharacterMove {
+        let start = objects[0].position;
+        CharacterController::default().move_and_slide(objects, grid, 0, start, 0.0, &motion)
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.02,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn walks_freely_on_the_floor() {
+        let (objects, grid) = world(Vec::new());
+        let result = walk(&objects, &grid, Vector3::new(2.0, 0.0, 1.0));
+        assert_close(result.position.x, 2.0);
+        assert_close(result.position.z, 1.0);
+        assert_close(result.position.y, -HALF - 0.01);
+        assert!(result.grounded);
+        assert!(result.hits.is_empty());
+    }
+
+    #[test]
+    fn slides_along_a_wall() {
+        let wall = block(
+            3,
+            Vector3::new(3.5, -2.0, 0.0),
+            Vector3::new(0.5, 2.0, 10.0),
+        );
+        let (objects, grid) = world(vec![wall]);
+        let result = walk(&objects, &grid, Vector3::new(5.0, 0.0, 5.0));
+        // Stopped by the wall on x, the rest of the move goes along it
+        assert_close(result.position.x, 3.0 - HALF);
+        assert!(result.position.x < 3.0 - HALF);
+        assert_close(result.position.z, 5.0);
+        assert_close(result.position.y, -HALF - 0.01);
+        assert!(result.grounded);
+        assert_eq!(result.hits, vec![objects[2].random_id]);
+    }
+
+    #[test]
+    fn does_not_tunnel_through_thin_walls() {
+        let wall = block(
+            3,
+            Vector3::new(3.0, -2.0, 0.0),
+            Vector3::new(0.05, 2.0, 10.0),
+        );
+        let (objects, grid) = world(vec![wall]);
+        let result = walk(&objects, &grid, Vector3::new(50.0, 0.0, 0.0));
+        assert!(result.position.x < 2.95 - HALF);
+        assert_close(result.position.x, 2.95 - HALF);
+    }
+
+    #[test]
+    fn climbs_low_steps_but_not_high_ones() {
+        let step = block(
+            3,
+            Vector3::new(3.0, -0.1, 0.0),
+            Vector3::new(1.0, 0.1, 10.0),
+        );
+        let (objects, grid) = world(vec![step]);
+        let result = walk(&objects, &grid, Vector3::new(3.0, 0.0, 0.0));
+        assert_close(result.position.x, 3.0);
+        // Standing on top of the step
+        assert_close(result.position.y, -0.2 - HALF - 0.01);
+        assert!(result.grounded);
+
+        let ledge = block(
+            3,
+            Vector3::new(3.0, -0.3, 0.0),
+            Vector3::new(1.0, 0.3, 10.0),
+        );
+        let (objects, grid) = world(vec![ledge]);
+        let result = walk(&objects, &grid, Vector3::new(3.0, 0.0, 0.0));
+        assert_close(result.position.x, 2.0 - HALF);
+        assert_close(result.position.y, -HALF - 0.01);
+    }
+
+    #[test]
+    fn lands_on_the_ground() {
+        let (mut objects, _) = world(Vec::new());
+        objects[0].position.y = -4.0;
+        let mut grid = SpatialGrid::new(4.0);
+        grid.sync(&objects);
+
+        let result = walk(&objects, &grid, Vector3::new(0.0, 1.0, 0.0));
+        assert_close(result.position.y, -3.0);
+        assert!(!result.grounded);
+
+        let result = walk(&objects, &grid, Vector3::new(0.0, 10.0, 0.0));
+        assert_close(result.position.y, -HALF - 0.01);
+        assert!(result.position.y < -HALF);
+        assert!(result.grounded);
+        assert_eq!(result.hits, vec![objects[1].random_id]);
+    }
+
+    #[test]
+    fn gets_pushed_out_of_what_it_starts_inside() {
+        let (mut objects, _) = world(Vec::new());
+        objects[0].position.y = -0.3;
+        let mut grid = SpatialGrid::new(4.0);
+        grid.sync(&objects);
+
+        let result = walk(&objects, &grid, Vector3::zeros());
+        assert!(result.position.y <= -HALF);
+        assert_close(result.position.y, -HALF - 0.01);
+        assert!(result.grounded);
+    }
+
+    #[test]
+    fn ignores_triggers_and_filtered_objects() {
+        let mut zone = block(
+            3,
+            Vector3::new(2.0, -1.0, 0.0),
+            Vector3::new(0.5, 1.0, 10.0),
+        );
+        zone.trigger = true;
+        let mut ghost = block(
+            4,
+            Vector3::new(4.0, -1.0, 0.0),
+            Vector3::new(0.5, 1.0, 10.0),
+        );
+        ghost.filter.mask = 1 << 1;
+        let (objects, grid) = world(vec![zone, ghost]);
+        let result = walk(&objects, &grid, Vector3::new(6.0, 0.0, 0.0));
+        assert_close(result.position.x, 6.0);
+        assert!(result.hits.is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+use std::path::PathBuf;
+
+pub const USAGE: &str = "Usage: mi_motor [run] [<project-dir>] [options]
+       mi_motor help
+
+Runs the game in <project-dir>, the current folder by default.
+Scripts, models, sounds and screenshots are relative to it.
+
+Options:
+    --config <file>   Settings of the game, <project-dir>/game.toml by default
+    --headless        Run without window or audio, drawing to an offscreen image
+    --frames <n>      Exit after <n> frames, 3600 by default with --headless
+    --seed <n>        Seed of math.random, object ids always count up from 1
+    -h, --help        Show this help";
+
+// Frames a headless run lasts without --frames, a minute at the headless frame rate
+pub const DEFAULT_HEADLESS_FRAMES: u64 = 3600;
+
+pub enum Command {
+    Run(RunOptions),
+    Help,
+}
+
+/// Options of `mi_motor run`.
+pub struct RunOptions {
+    pub project: PathBuf,
+    pub config: Option<PathBuf>,
+    pub headless: bool,
+    pub frames: Option<u64>,
+    pub seed: Option<u64>,
+}
+
+impl RunOptions {
+    /// The config given with `--config`, or the `game.toml` of the project.
+    pub fn config_path(&self) -> PathBuf {
+        match &self.config {
+            Some(config) => config.clone(),
+            None => self.project.join("game.toml"),
+        }
+    }
+}
+
+/// Parses the arguments, without the program name.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.into_iter().peekable();
+    match args.peek().map(String::as_str) {
+        Some("run") => {
+            args.next();
+        }
+        Some("help") => return Ok(Command::Help),
+        _ => {}
+    }
+
+    let mut options = RunOptions {
+        project: PathBuf::from("."),
+        config: None,
+        headless: false,
+        frames: None,
+        seed: None,
+    };
+    let mut project = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--config" => options.config = Some(PathBuf::from(value(&arg, args.next())?)),
+            "--headless" => options.headless = true,
+            "--frames" => options.frames = Some(number(&arg, args.next())?),
+            "--seed" => options.seed = Some(number(&arg, args.next())?),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ => {
+                if project.is_some() {
+                    return Err(format!("Unexpected argument: {}", arg));
+                }
+                project = Some(PathBuf::from(arg));
+            }
+        }
+    }
+    if let Some(project) = project {
+        options.project = project;
+    }
+    // Without window nothing else ends the game
+    if options.headless && options.frames.is_none() {
+        options.frames = Some(DEFAULT_HEADLESS_FRAMES);
+    }
+    Ok(Command::Run(options))
+}
+
+fn value(option: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("Missing value for {}", option))
+}
+
+fn number(option: &str, value_arg: Option<String>) -> Result<u64, String> {
+    let text = value(option, value_arg)?;
+    text.parse()
+        .map_err(|_| format!("Invalid number for {}: {}", option, text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn run_options(args: &[&str]) -> RunOptions {
+        match parse_args(args) {
+            Ok(Command::Run(options)) => options,
+            Ok(Command::Help) => panic!("{:?} asked for the help", args),
+            Err(e) => panic!("{:?} failed: {}", args, e),
+        }
+    }
+
+    #[test]
+    fn help_wins_over_everything_else() {
+        for args in [
+            &["--help"][..],
+            &["-h"],
+            &["help"],
+            &["help", "--frames"],
+            &["run", "game", "-h"],
+        ] {
+            assert!(matches!(parse_args(args), Ok(Command::Help)), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn help_is_only_a_command_in_first_place() {
+        assert_eq!(run_options(&["run", "help"]).project, PathBuf::from("help"));
+        assert_eq!(
+            parse_args(&["game", "help"]).err(),
+            Some("Unexpected argument: help".to_string())
+        );
+    }
+
+    #[test]
+    fn headless_runs_end_after_a_default_number_of_frames() {
+        let options = run_options(&["--headless"]);
+        assert_eq!(options.frames, Some(DEFAULT_HEADLESS_FRAMES));
+        let options = run_options(&["--headless", "--frames", "10"]);
+        assert_eq!(options.frames, Some(10));
+    }
+
+    #[test]
+    fn defaults_to_the_current_folder() {
+        for args in [&[][..], &["run"]] {
+            let options = run_options(args);
+            assert_eq!(options.project, PathBuf::from("."));
+            assert_eq!(options.config_path(), PathBuf::from("./game.toml"));
+            assert!(!options.headless);
+            assert_eq!(options.frames, None);
+            assert_eq!(options.seed, None);
+        }
+    }
+
+    #[test]
+    fn reads_the_project_and_every_option() {
+        let options = run_options(&[
+            "run",
+            "../my-game",
+            "--headless",
+            "--frames",
+            "600",
+            "--seed",
+            "7",
+            "--config",
+            "other.toml",
+        ]);
+        assert_eq!(options.project, PathBuf::from("../my-game"));
+        assert!(options.headless);
+        assert_eq!(options.frames, Some(600));
+        assert_eq!(options.seed, Some(7));
+        assert_eq!(options.config_path(), PathBuf::from("other.toml"));
+
+        // Without `run` the first argument is the project too
+        let options = run_options(&["my-game"]);
+        assert_eq!(options.config_path(), PathBuf::from("my-game/game.toml"));
+    }
+
+    #[test]
+    fn numbers_must_be_given_and_valid() {
+        for args in [
+            &["--frames"][..],
+            &["--seed"],
+            &["--frames", "ten"],
+            &["--seed", "-1"],
+            &["--frames", "1.5"],
+            &["--config"],
+        ] {
+            assert!(parse_args(args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_options_and_extra_arguments() {
+        assert_eq!(
+            parse_args(&["--fast"]).err(),
+            Some("Unknown option: --fast".to_string())
+        );
+        assert_eq!(
+            parse_args(&["one", "two"]).err(),
+            Some("Unexpected argument: two".to_string())
+        );
+    }
+}
@@ -0,0 +1,815 @@
+use na::{Matrix3, Vector3};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::object3d::Object3d;
+use crate::spatial::SpatialGrid;
+
+// GJK converges in a few iterations, this only guards against degenerate shapes
+const GJK_MAX_ITERATIONS: usize = 64;
+const EPA_MAX_ITERATIONS: usize = 64;
+// EPA stops when the polytope grows less than this towards the closest face
+const EPA_TOLERANCE: f32 = 1e-4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColliderKind {
+    Sphere,
+    Aabb,
+    Obb,
+    Hull,
+}
+
+impl ColliderKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sphere" => Some(ColliderKind::Sphere),
+            "aabb" => Some(ColliderKind::Aabb),
+            "obb" => Some(ColliderKind::Obb),
+            "hull" => Some(ColliderKind::Hull),
+            _ => None,
+        }
+    }
+}
+
+/// Shape of an object used for collisions, in model space.
+#[derive(Clone, Debug)]
+pub enum Collider {
+    Sphere {
+        center: Vector3<f32>,
+        radius: f32,
+    },
+    // Box that stays aligned to the world axes when the object turns
+    Aabb {
+        center: Vector3<f32>,
+        half_extents: Vector3<f32>,
+    },
+    // Box that turns with the object
+    Obb {
+        center: Vector3<f32>,
+        half_extents: Vector3<f32>,
+    },
+    // The convex hull of the points, GJK only needs their furthest point in a direction
+    Hull {
+        points: Vec<Vector3<f32>>,
+    },
+}
+
+impl Collider {
+    /// The smallest collider of the kind around the points of a mesh.
+    pub fn fit(kind: ColliderKind, points: &[Vector3<f32>]) -> Self {
+        let bounds = Bounds::of_points(points.iter().copied());
+        let center = (bounds.min + bounds.max) / 2.0;
+        let half_extents = (bounds.max - bounds.min) / 2.0;
+        match kind {
+            ColliderKind::Sphere => Collider::Sphere {
+                center,
+                radius: points
+                    .iter()
+                    .map(|p| (p - center).norm())
+                    .fold(0.0, f32::max),
+            },
+            ColliderKind::Aabb => Collider::Aabb {
+                center,
+                half_extents,
+            },
+            ColliderKind::Obb => Collider::Obb {
+                center,
+                half_extents,
+            },
+            ColliderKind::Hull => Collider::Hull {
+                points: points.to_vec(),
+            },
+        }
+    }
+
+    /// Places the collider in the world with the transform of its object.
+    pub fn world_shape(&self, position: &Vector3<f32>, rotation: &Matrix3<f32>) -> WorldShape {
+        match self {
+            Collider::Sphere { center, radius } => WorldShape::Sphere {
+                center: rotation * center + position,
+                radius: *radius,
+            },
+            Collider::Aabb {
+                center,
+                half_extents,
+            } => WorldShape::Box {
+                center: rotation * center + position,
+                axes: Matrix3::identity(),
+                half_extents: *half_extents,
+            },
+            Collider::Obb {
+                center,
+                half_extents,
+            } => WorldShape::Box {
+                center: rotation * center + position,
+                axes: *rotation,
+                half_extents: *half_extents,
+            },
+            Collider::Hull { points } => {
+                WorldShape::Points(points.iter().map(|p| rotation * p + position).collect())
+            }
+        }
+    }
+}
+
+/// Which objects collide with which. Two objects collide only when each one has a layer in the
+/// mask of the other.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CollisionFilter {
+    // Bit n set for layer n + 1, objects are on layer 1 by default
+    pub layers: u32,
+    // The layers it collides with, all by default
+    pub mask: u32,
+}
+
+impl Default for CollisionFilter {
+    fn default() -> Self {
+        CollisionFilter {
+            layers: 1,
+            mask: u32::MAX,
+        }
+    }
+}
+
+impl CollisionFilter {
+    pub fn collides_with(&self, other: &CollisionFilter) -> bool {
+        self.layers & other.mask != 0 && other.layers & self.mask != 0
+    }
+}
+
+/// A collider placed in the world.
+#[derive(Clone, Debug)]
+pub enum WorldShape {
+    Sphere {
+        center: Vector3<f32>,
+        radius: f32,
+    },
+    Box {
+        center: Vector3<f32>,
+        // The columns are the directions of the sides
+        axes: Matrix3<f32>,
+        half_extents: Vector3<f32>,
+    },
+    Points(Vec<Vector3<f32>>),
+}
+
+impl WorldShape {
+    /// The furthest point of the shape in the direction.
+    pub fn support(&self, dir: &Vector3<f32>) -> Vector3<f32> {
+        match self {
+            WorldShape::Sphere { center, radius } => {
+                let norm = dir.norm();
+                if norm > f32::EPSILON {
+                    center + dir * (*radius / norm)
+                } else {
+                    *center
+                }
+            }
+            WorldShape::Box {
+                center,
+                axes,
+                half_extents,
+            } => {
+                let mut point = *center;
+                for i in 0..3 {
+                    let axis = axes.column(i);
+                    point += axis * half_extents[i] * axis.dot(dir).signum();
+                }
+                point
+            }
+            WorldShape::Points(points) => points
+                .iter()
+                .copied()
+                .max_by(|a, b| a.dot(dir).total_cmp(&b.dot(dir)))
+                .unwrap_or_else(Vector3::zeros),
+        }
+    }
+
+    pub fn bounds(&self) -> Bounds {
+        match self {
+            WorldShape::Sphere { center, radius } => Bounds {
+                min: center.add_scalar(-radius),
+                max: center.add_scalar(*radius),
+            },
+            WorldShape::Box {
+                center,
+                axes,
+                half_extents,
+            } => {
+                let extent = axes.abs() * half_extents;
+                Bounds {
+                    min: center - extent,
+                    max: center + extent,
+                }
+            }
+            WorldShape::Points(points) => Bounds::of_points(points.iter().copied()),
+        }
+    }
+}
+
+/// Axis aligned box used by the broad phase.
+#[derive(Clone, Copy, Debug)]
+pub struct Bounds {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Bounds {
+    /// The box around the points, a point at the origin if there are none.
+    pub fn of_points(points: impl Iterator<Item = Vector3<f32>>) -> Self {
+        let mut bounds: Option<Bounds> = None;
+        for p in points {
+            bounds = Some(match bounds {
+                Some(b) => Bounds {
+                    min: b.min.inf(&p),
+                    max: b.max.sup(&p),
+                },
+                None => Bounds { min: p, max: p },
+            });
+        }
+        bounds.unwrap_or(Bounds {
+            min: Vector3::zeros(),
+            max: Vector3::zeros(),
+        })
+    }
+
+    pub fn overlaps(&self, other: &Bounds) -> bool {
+        (0..3).all(|i| self.min[i] <= other.max[i] && other.min[i] <= self.max[i])
+    }
+
+    /// The box around both boxes.
+    pub fn union(&self, other: &Bounds) -> Bounds {
+        Bounds {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    /// Part of the ray inside the box, as distances along it from `origin`, clipped to
+    /// `[0, max_distance]`. Uses the slab method.
+    pub fn ray_interval(
+        &self,
+        origin: &Vector3<f32>,
+        dir: &Vector3<f32>,
+        max_distance: f32,
+    ) -> Option<(f32, f32)> {
+        let mut near = 0.0f32;
+        let mut far = max_distance;
+        for i in 0..3 {
+            if dir[i].abs() < f32::EPSILON {
+                if origin[i] < self.min[i] || origin[i] > self.max[i] {
+                    return None;
+                }
+                continue;
+            }
+            let t1 = (self.min[i] - origin[i]) / dir[i];
+            let t2 = (self.max[i] - origin[i]) / dir[i];
+            near = near.max(t1.min(t2));
+            far = far.min(t1.max(t2));
+            if near > far {
+                return None;
+            }
+        }
+        Some((near, far))
+    }
+}
+
+/// Pairs of boxes that overlap, as indices into `bounds`.
+/// Sweep and prune: the boxes are sorted along x and only the ones overlapping on x are tested.
+/// The engine finds its pairs with `SpatialGrid`; this is kept as the baseline the
+/// `spatial_index` benchmark compares the grid against.
+pub fn broad_phase(bounds: &[Bounds]) -> Vec<(usize, usize)> {
+    let mut order: Vec<usize> = (0..bounds.len()).collect();
+    order.sort_by(|&a, &b| bounds[a].min.x.total_cmp(&bounds[b].min.x));
+
+    let mut pairs = Vec::new();
+    let mut active: Vec<usize> = Vec::new();
+    for &i in &order {
+        active.retain(|&j| bounds[j].max.x >= bounds[i].min.x);
+        for &j in &active {
+            if bounds[i].overlaps(&bounds[j]) {
+                pairs.push((j.min(i), j.max(i)));
+            }
+        }
+        active.push(i);
+    }
+    pairs
+}
+
+fn minkowski_support(a: &WorldShape, b: &WorldShape, dir: &Vector3<f32>) -> Vector3<f32> {
+    a.support(dir) - b.support(&-dir)
+}
+
+/// True when the directions point to the same side.
+fn same_direction(a: &Vector3<f32>, b: &Vector3<f32>) -> bool {
+    a.dot(b) > 0.0
+}
+
+/// Whether the shapes overlap, with the Gilbert-Johnson-Keerthi algorithm.
+/// On overlap it returns the last simplex, the newest point first, which encloses the origin
+/// when it has four points. Shapes that only touch do not overlap.
+pub fn gjk(a: &WorldShape, b: &WorldShape) -> Option<Vec<Vector3<f32>>> {
+    let mut dir = Vector3::x();
+    let mut simplex = vec![minkowski_support(a, b, &dir)];
+    dir = -simplex[0];
+
+    for _ in 0..GJK_MAX_ITERATIONS {
+        let point = minkowski_support(a, b, &dir);
+        if point.dot(&dir) <= 0.0 {
+            return None;
+        }
+        simplex.insert(0, point);
+        if next_simplex(&mut simplex, &mut dir) {
+            return Some(simplex);
+        }
+    }
+    None
+}
+
+/// Keeps the part of the simplex closest to the origin and points `dir` to it.
+/// Returns true when the simplex encloses the origin.
+fn next_simplex(simplex: &mut Vec<Vector3<f32>>, dir: &mut Vector3<f32>) -> bool {
+    match simplex.len() {
+        2 => line(simplex, dir),
+        3 => triangle(simplex, dir),
+        _ => tetrahedron(simplex, dir),
+    }
+}
+
+fn line(simplex: &mut Vec<Vector3<f32>>, dir: &mut Vector3<f32>) -> bool {
+    let (a, b) = (simplex[0], simplex[1]);
+    let ab = b - a;
+    let ao = -a;
+    if same_direction(&ab, &ao) {
+        *dir = ab.cross(&ao).cross(&ab);
+        // The origin is on the line, any perpendicular direction keeps the search going
+        if dir.norm_squared() <= f32::EPSILON * ab.norm_squared() {
+            *dir = ab.cross(&Vector3::x());
+            if dir.norm_squared() <= f32::EPSILON * ab.norm_squared() {
+                *dir = ab.cross(&Vector3::y());
+            }
+        }
+    } else {
+        *simplex = vec![a];
+        *dir = ao;
+    }
+    false
+}
+
+fn triangle(simplex: &mut Vec<Vector3<f32>>, dir: &mut Vector3<f32>) -> bool {
+    let (a, b, c) = (simplex[0], simplex[1], simplex[2]);
+    let ab = b - a;
+    let ac = c - a;
+    let ao = -a;
+    let abc = ab.cross(&ac);
+
+    if same_direction(&abc.cross(&ac), &ao) {
+        if same_direction(&ac, &ao) {
+            *simplex = vec![a, c];
+            *dir = ac.cross(&ao).cross(&ac);
+            false
+        } else {
+            *simplex = vec![a, b];
+            line(simplex, dir)
+        }
+    } else if same_direction(&ab.cross(&abc), &ao) {
+        *simplex = vec![a, b];
+        line(simplex, dir)
+    } else if same_direction(&abc, &ao) {
+        *dir = abc;
+        false
+    } else {
+        *simplex = vec![a, c, b];
+        *dir = -abc;
+        false
+    }
+}
+
+fn tetrahedron(simplex: &mut Vec<Vector3<f32>>, dir: &mut Vector3<f32>) -> bool {
+    let (a, b, c, d) = (simplex[0], simplex[1], simplex[2], simplex[3]);
+    let ab = b - a;
+    let ac = c - a;
+    let ad = d - a;
+    let ao = -a;
+
+    if same_direction(&ab.cross(&ac), &ao) {
+        *simplex = vec![a, b, c];
+        return triangle(simplex, dir);
+    }
+    if same_direction(&ac.cross(&ad), &ao) {
+        *simplex = vec![a, c, d];
+        return triangle(simplex, dir);
+    }
+    if same_direction(&ad.cross(&ab), &ao) {
+        *simplex = vec![a, d, b];
+        return triangle(simplex, dir);
+    }
+    true
+}
+
+/// How two overlapping shapes touch.
+#[derive(Clone, Copy, Debug)]
+pub struct Contact {
+    // Unit vector from the first shape to the second one
+    pub normal: Vector3<f32>,
+    // Distance to move them apart along the normal to stop the overlap
+    pub depth: f32,
+}
+
+/// Finds the contact of two overlapping shapes with the Expanding Polytope Algorithm,
+/// starting from the simplex returned by `gjk`.
+pub fn epa(simplex: &[Vector3<f32>], a: &WorldShape, b: &WorldShape) -> Option<Contact> {
+    if simplex.len() < 4 {
+        return None;
+    }
+    let mut vertices = simplex.to_vec();
+    let mut faces: Vec<[usize; 3]> = vec![[0, 1, 2], [0, 3, 1], [0, 2, 3], [1, 3, 2]];
+
+    for _ in 0..EPA_MAX_ITERATIONS {
+        // The closest face to the origin, with its normal pointing out of the polytope
+        let mut closest: Option<(Vector3<f32>, f32)> = None;
+        for face in faces.iter_mut() {
+            let Some((mut normal, mut distance)) = face_normal(&vertices, face) else {
+                continue;
+            };
+            // The origin is inside, faces facing it are wound the wrong way
+            if distance < 0.0 {
+                face.swap(1, 2);
+                normal = -normal;
+                distance = -distance;
+            }
+            if closest.is_none_or(|(_, closest_distance)| distance < closest_distance) {
+                closest = Some((normal, distance));
+            }
+        }
+        let (normal, distance) = closest?;
+
+        let point = minkowski_support(a, b, &normal);
+        if point.dot(&normal) - distance < EPA_TOLERANCE {
+            return Some(Contact {
+                normal,
+                depth: distance,
+            });
+        }
+
+        // Remove the faces the new point sees and close the hole with faces to the point
+        let mut edges: Vec<(usize, usize)> = Vec::new();
+        faces.retain(|face| {
+            let Some((face_normal, _)) = face_normal(&vertices, face) else {
+                return true;
+            };
+            if face_normal.dot(&(point - vertices[face[0]])) <= 0.0 {
+                return true;
+            }
+            for (from, to) in [(face[0], face[1]), (face[1], face[2]), (face[2], face[0])] {
+                // An edge shared by two removed faces is inside the hole
+                match edges.iter().position(|&edge| edge == (to, from)) {
+                    Some(i) => {
+                        edges.swap_remove(i);
+                    }
+                    None => edges.push((from, to)),
+                }
+            }
+            false
+        });
+        if edges.is_empty() {
+            return Some(Contact {
+                normal,
+                depth: distance,
+            });
+        }
+        vertices.push(point);
+        let new_vertex = vertices.len() - 1;
+        for (from, to) in edges {
+            faces.push([from, to, new_vertex]);
+        }
+    }
+    None
+}
+
+/// Normal of a face by its winding and the distance of its plane to the origin along it.
+/// `None` for degenerate faces.
+fn face_normal(vertices: &[Vector3<f32>], face: &[usize; 3]) -> Option<(Vector3<f32>, f32)> {
+    let (a, b, c) = (vertices[face[0]], vertices[face[1]], vertices[face[2]]);
+    let normal = (b - a).cross(&(c - a)).try_normalize(f32::EPSILON)?;
+    Some((normal, normal.dot(&a)))
+}
+
+/// Collisions that started, continued and ended on the last update, as pairs of object ids
+/// with the lowest id first. Pairs with a trigger only enter and exit.
+#[derive(Default, Debug)]
+pub struct CollisionEvents {
+    pub enter: Vec<(u32, u32)>,
+    pub stay: Vec<(u32, u32)>,
+    pub exit: Vec<(u32, u32)>,
+    pub trigger_enter: Vec<(u32, u32)>,
+    pub trigger_exit: Vec<(u32, u32)>,
+    // Contacts between objects where at least one has a rigid body, as indices into the
+    // objects, for the collision response
+    pub contacts: Vec<(usize, usize, Contact)>,
+}
+
+/// Finds the objects that touch each other. Objects without collider or not rendered are
+/// ignored, and so are pairs whose filters do not collide.
+#[derive(Default)]
+pub struct CollisionWorld {
+    // Sorted so the callbacks always run in the same order, replays depend on it
+    touching: BTreeSet<(u32, u32)>,
+    // Pairs where one of the objects is a trigger, they never push each other
+    overlapping: BTreeSet<(u32, u32)>,
+}
+
+impl CollisionWorld {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Finds the collisions of this step. `grid` must be synced with `objects`, it gives the
+    /// pairs worth testing.
+    pub fn update(&mut self, objects: &[Object3d], grid: &SpatialGrid) -> CollisionEvents {
+        // Placed once per object, only for the objects near another one
+        let mut shapes: HashMap<usize, Option<WorldShape>> = HashMap::new();
+        let mut touching = BTreeSet::new();
+        let mut overlapping = BTreeSet::new();
+        let mut contacts = Vec::new();
+        for (i, j) in grid.pairs() {
+            let (Some(a), Some(b)) = (objects.get(i), objects.get(j)) else {
+                continue;
+            };
+            if a.collider.is_none() || b.collider.is_none() || !a.filter.collides_with(&b.filter) {
+                continue;
+            }
+            shapes.entry(i).or_insert_with(|| a.collision_shape());
+            shapes.entry(j).or_insert_with(|| b.collision_shape());
+            let (Some(shape_a), Some(shape_b)) = (&shapes[&i], &shapes[&j]) else {
+                continue;
+            };
+            if !shape_a.bounds().overlaps(&shape_b.bounds()) {
+                continue;
+            }
+            let Some(simplex) = gjk(shape_a, shape_b) else {
+                continue;
+            };
+            let pair = (a.random_id.min(b.random_id), a.random_id.max(b.random_id));
+            if a.trigger || b.trigger {
+                overlapping.insert(pair);
+                continue;
+            }
+            touching.insert(pair);
+            if a.body.is_some() || b.body.is_some() {
+                if let Some(contact) = epa(&simplex, shape_a, shape_b) {
+                    contacts.push((i, j, contact));
+                }
+            }
+        }
+
+        let events = CollisionEvents {
+            enter: touching.difference(&self.touching).copied().collect(),
+            stay: touching.intersection(&self.touching).copied().collect(),
+            exit: self.touching.difference(&touching).copied().collect(),
+            trigger_enter: overlapping.difference(&self.overlapping).copied().collect(),
+            trigger_exit: self.overlapping.difference(&overlapping).copied().collect(),
+            contacts,
+        };
+        self.touching = touching;
+        self.overlapping = overlapping;
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object3d::Object3d;
+    use crate::physics::RigidBody;
+    use crate::test_util::cube_at;
+
+    fn sphere(x: f32, y: f32, z: f32, radius: f32) -> WorldShape {
+        WorldShape::Sphere {
+            center: Vector3::new(x, y, z),
+            radius,
+        }
+    }
+
+    fn cube(x: f32, yaw: f32) -> WorldShape {
+        WorldShape::Box {
+            center: Vector3::new(x, 0.0, 0.0),
+            axes: Object3d::yaw_matrix(yaw),
+            half_extents: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    fn cube_object(id: u32, x: f32) -> Object3d {
+        let mut obj = cube_at(id, Vector3::new(x, 0.0, 0.0));
+        obj.set_collider(Some(ColliderKind::Aabb));
+        obj
+    }
+
+    #[test]
+    fn gjk_tells_overlapping_from_separated_shapes() {
+        assert!(gjk(&sphere(0.0, 0.0, 0.0, 1.0), &sphere(1.5, 0.0, 0.0, 1.0)).is_some());
+        assert!(gjk(&sphere(0.0, 0.0, 0.0, 1.0), &sphere(2.5, 0.0, 0.0, 1.0)).is_none());
+        assert!(gjk(&cube(0.0, 0.0), &cube(1.9, 0.0)).is_some());
+        assert!(gjk(&cube(0.0, 0.0), &cube(2.1, 0.0)).is_none());
+        assert!(gjk(&cube(0.0, 0.0), &sphere(0.0, 1.9, 0.0, 1.0)).is_some());
+    }
+
+    #[test]
+    fn gjk_separates_shapes_whose_boxes_overlap() {
+        // Turned 45 degrees, its box reaches the other cube but its faces do not
+        let turned = WorldShape::Box {
+            center: Vector3::new(2.2, 0.0, 2.2),
+            axes: Object3d::yaw_matrix(std::f32::consts::FRAC_PI_4),
+            half_extents: Vector3::new(1.0, 1.0, 1.0),
+        };
+        assert!(turned.bounds().overlaps(&cube(0.0, 0.0).bounds()));
+        assert!(gjk(&cube(0.0, 0.0), &turned).is_none());
+
+        let hull = WorldShape::Points(vec![
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(2.0, 0.0, 0.0),
+            Vector3::new(0.0, 2.0, 0.0),
+            Vector3::new(0.0, 0.0, 2.0),
+        ]);
+        assert!(gjk(&hull, &sphere(0.3, 0.3, 0.3, 0.1)).is_some());
+        assert!(gjk(&hull, &sphere(1.5, 1.5, 1.5, 0.5)).is_none());
+    }
+
+    #[test]
+    fn broad_phase_finds_every_overlapping_pair() {
+        let bounds: Vec<Bounds> = (0..40)
+            .map(|i| {
+                let min = Vector3::new((i * 7 % 13) as f32, (i * 5 % 11) as f32, (i % 3) as f32);
+                Bounds {
+                    min,
+                    max: min.add_scalar(2.5),
+                }
+            })
+            .collect();
+        let mut expected = Vec::new();
+        for i in 0..bounds.len() {
+            for j in i + 1..bounds.len() {
+                if bounds[i].overlaps(&bounds[j]) {
+                    expected.push((i, j));
+                }
+            }
+        }
+        let mut pairs = broad_phase(&bounds);
+        pairs.sort_unstable();
+        assert!(!expected.is_empty());
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn ray_interval_clips_the_ray_to_the_box() {
+        let bounds = Bounds {
+            min: Vector3::new(-1.0, -1.0, -1.0),
+            max: Vector3::new(1.0, 1.0, 1.0),
+        };
+        let origin = Vector3::new(-5.0, 0.0, 0.0);
+        let dir = Vector3::x();
+        assert_eq!(bounds.ray_interval(&origin, &dir, 100.0), Some((4.0, 6.0)));
+        assert_eq!(bounds.ray_interval(&origin, &dir, 5.0), Some((4.0, 5.0)));
+        assert_eq!(bounds.ray_interval(&origin, &dir, 3.0), None);
+        assert_eq!(bounds.ray_interval(&origin, &-dir, 100.0), None);
+        assert_eq!(
+            bounds.ray_interval(&Vector3::new(-5.0, 2.0, 0.0), &dir, 100.0),
+            None
+        );
+    }
+
+    #[test]
+    fn filters_collide_only_when_each_is_in_the_mask_of_the_other() {
+        let default = CollisionFilter::default();
+        let projectile = CollisionFilter {
+            layers: 1 << 1,
+            mask: 1 << 2,
+        };
+        let target = CollisionFilter {
+            layers: 1 << 2,
+            mask: u32::MAX,
+        };
+        let ghost = CollisionFilter { layers: 1, mask: 0 };
+        assert!(default.collides_with(&default));
+        assert!(projectile.collides_with(&target));
+        assert!(target.collides_with(&projectile));
+        assert!(!projectile.collides_with(&default));
+        assert!(!default.collides_with(&projectile));
+        assert!(!ghost.collides_with(&default));
+        assert!(!ghost.collides_with(&ghost));
+    }
+
+    #[test]
+    fn collisions_enter_stay_and_exit() {
+        let mut objects = vec![cube_object(1, 0.0), cube_object(2, 1.5)];
+        let mut grid = SpatialGrid::new(4.0);
+        let mut world = CollisionWorld::new();
+
+        grid.sync(&objects);
+        let events = world.update(&objects, &grid);
+        assert_eq!(events.enter, vec![(1, 2)]);
+        assert!(events.stay.is_empty() && events.exit.is_empty());
+
+        let events = world.update(&objects, &grid);
+        assert!(events.enter.is_empty());
+        assert_eq!(events.stay, vec![(1, 2)]);
+
+        objects[1].position.x = 5.0;
+        grid.sync(&objects);
+        let events = world.update(&objects, &grid);
+        assert!(events.enter.is_empty() && events.stay.is_empty());
+        assert_eq!(events.exit, vec![(1, 2)]);
+    }
+
+    #[test]
+    fn filters_and_triggers_change_the_events() {
+        let mut objects = vec![
+            cube_object(1, 0.0),
+            cube_object(2, 1.5),
+            cube_object(3, -1.5),
+        ];
+        objects[1].filter = CollisionFilter {
+            layers: 1 << 1,
+            mask: 1 << 2,
+        };
+        objects[2].trigger = true;
+        let mut grid = SpatialGrid::new(4.0);
+        grid.sync(&objects);
+        let events = CollisionWorld::new().update(&objects, &grid);
+
+        assert!(events.enter.is_empty());
+        assert_eq!(events.trigger_enter, vec![(1, 3)]);
+        assert!(events.contacts.is_empty());
+    }
+
+    fn contact(a: &WorldShape, b: &WorldShape) -> Contact {
+        let simplex = gjk(a, b).expect("The shapes overlap");
+        epa(&simplex, a, b).expect("EPA finds the contact")
+    }
+
+    #[test]
+    fn epa_pushes_boxes_apart_along_the_shallowest_axis() {
+        let hit = contact(&cube(0.0, 0.0), &cube(1.5, 0.0));
+        assert!((hit.depth - 0.5).abs() < 1e-3, "depth {}", hit.depth);
+        assert!(
+            (hit.normal - Vector3::x()).norm() < 1e-3,
+            "normal {}",
+            hit.normal
+        );
+
+        // The normal goes from the first shape to the second one
+        let hit = contact(&cube(1.5, 0.0), &cube(0.0, 0.0));
+        assert!(
+            (hit.normal + Vector3::x()).norm() < 1e-3,
+            "normal {}",
+            hit.normal
+        );
+
+        let below = WorldShape::Box {
+            center: Vector3::new(0.3, 1.8, 0.0),
+            axes: Matrix3::identity(),
+            half_extents: Vector3::new(1.0, 1.0, 1.0),
+        };
+        let hit = contact(&cube(0.0, 0.0), &below);
+        assert!((hit.depth - 0.2).abs() < 1e-3, "depth {}", hit.depth);
+        assert!(
+            (hit.normal - Vector3::y()).norm() < 1e-3,
+            "normal {}",
+            hit.normal
+        );
+    }
+
+    #[test]
+    fn epa_finds_the_depth_of_spheres() {
+        let a = sphere(0.0, 0.0, 0.0, 1.0);
+        let b = sphere(1.0, 1.0, 0.0, 1.0);
+        let hit = contact(&a, &b);
+        let expected = 2.0 - 2f32.sqrt();
+        // The sphere is approximated by the polytope, so only close
+        assert!((hit.depth - expected).abs() < 0.02, "depth {}", hit.depth);
+        let diagonal = Vector3::new(1.0, 1.0, 0.0).normalize();
+        assert!(hit.normal.dot(&diagonal) > 0.99, "normal {}", hit.normal);
+    }
+
+    #[test]
+    fn contacts_are_reported_only_with_bodies() {
+        let mut objects = vec![cube_object(1, 0.0), cube_object(2, 1.5)];
+        let mut grid = SpatialGrid::new(4.0);
+        grid.sync(&objects);
+        assert!(CollisionWorld::new()
+            .update(&objects, &grid)
+            .contacts
+            .is_empty());
+
+        objects[1].body = Some(RigidBody::default());
+        let events = CollisionWorld::new().update(&objects, &grid);
+        let [(0, 1, hit)] = events.contacts[..] else {
+            panic!("one contact between the cubes, got {:?}", events.contacts);
+        };
+        assert!((hit.depth - 0.5).abs() < 1e-3, "depth {}", hit.depth);
+        assert!(
+            (hit.normal - Vector3::x()).norm() < 1e-3,
+            "normal {}",
+            hit.normal
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::audio_backend::BackendKind;
+
+/// Settings of a game, read from its `game.toml`. Every field is optional.
+///
+/// ```toml
+/// [window]
+/// width = 1600
+/// height = 1200
+/// title = "Rust 3D Motor"
+/// fullscreen = false
+///
+/// [renderer]
+/// vsync = "auto"
+/// clear_color = [0.0, 0.0, 0.0]
+/// depth_fade = [20.0, 100.0]
+/// line_width = 1.5
+/// msaa = 1
+///
+/// [camera]
+/// fov = 60.0
+/// near = 0.1
+/// far = 1000.0
+///
+/// [audio]
+/// backend = "auto"
+///
+/// [simulation]
+/// hz = 60.0
+/// max_frame_time = 0.25
+/// gravity = [0.0, 9.81, 0.0]
+/// cell_size = 10.0
+///
+/// [scripts]
+/// start = "scripting/start.lua"
+/// update = "scripting/update.lua"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameConfig {
+    pub window: WindowConfig,
+    pub renderer: RendererConfig,
+    pub camera: CameraConfig,
+    pub audio: AudioConfig,
+    pub simulation: SimulationConfig,
+    pub scripts: ScriptsConfig,
+}
+
+impl GameConfig {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read the config {}: {}", path.display(), e))?;
+        let config: GameConfig = toml::from_str(&text)
+            .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+        config
+            .validate()
+            .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    /// Checks the values the window, renderer, camera and simulation can not work with.
+    pub fn validate(&self) -> Result<(), String> {
+        let window = &self.window;
+        if window.width == 0 || window.height == 0 {
+            return Err(format!(
+                "window size must not be zero, got {}x{}",
+                window.width, window.height
+            ));
+        }
+        let line_width = self.renderer.line_width;
+        if !line_width.is_finite() || line_width <= 0.0 {
+            return Err(format!(
+                "renderer.line_width must be a positive number, got {}",
+                line_width
+            ));
+        }
+        let camera = &self.camera;
+        if !(camera.fov > 0.0 && camera.fov < 180.0) {
+            return Err(format!(
+                "camera.fov must be between 0 and 180 degrees, got {}",
+                camera.fov
+            ));
+        }
+        if !camera.near.is_finite() || camera.near <= 0.0 {
+            return Err(format!(
+                "camera.near must be a positive number, got {}",
+                camera.near
+            ));
+        }
+        if !camera.far.is_finite() || camera.far <= camera.near {
+            return Err(format!(
+                "camera.far must be a number past camera.near = {}, got {}",
+                camera.near, camera.far
+            ));
+        }
+        let simulation = &self.simulation;
+        if !simulation.hz.is_finite() || simulation.hz <= 0.0 {
+            return Err(format!(
+                "simulation.hz must be a positive number, got {}",
+                simulation.hz
+            ));
+        }
+        if !simulation.max_frame_time.is_finite() || simulation.max_frame_time <= 0.0 {
+            return Err(format!(
+                "simulation.max_frame_time must be a positive number of seconds, got {}",
+                simulation.max_frame_time
+            ));
+        }
+        // A shorter frame could never fit a whole step, so the simulation would not run
+        if simulation.max_frame_time < 1.0 / simulation.hz {
+            return Err(format!(
+                "simulation.max_frame_time must be at least one step of 1/hz = {} seconds, got {}",
+                1.0 / simulation.hz,
+                simulation.max_frame_time
+            ));
+        }
+        let cell_size = simulation.cell_size;
+        if !cell_size.is_finite() || cell_size <= 0.0 {
+            return Err(format!(
+                "simulation.cell_size must be a positive number, got {}",
+                cell_size
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    pub width: u32,
+    pub height: u32,
+    pub title: String,
+    // Borderless on the current monitor
+    pub fullscreen: bool,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        WindowConfig {
+            width: 1600,
+            height: 1200,
+            title: "Rust 3D Motor".to_string(),
+            fullscreen: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Vsync {
+    // Vsync when available
+    #[default]
+    Auto,
+    On,
+    Off,
+    // Vsync without blocking, it falls back to on when not supported
+    Mailbox,
+}
+
+impl Vsync {
+    pub fn present_mode(self) -> wgpu::PresentMode {
+        match self {
+            Vsync::Auto => wgpu::PresentMode::AutoVsync,
+            Vsync::On => wgpu::PresentMode::Fifo,
+            Vsync::Off => wgpu::PresentMode::AutoNoVsync,
+            Vsync::Mailbox => wgpu::PresentMode::Mailbox,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RendererConfig {
+    pub vsync: Vsync,
+    // RGB from 0 to 1
+    pub clear_color: [f64; 3],
+    // Depths where the lines start to fade out and where they disappear, none by default
+    pub depth_fade: Option<[f32; 2]>,
+    // Width of the wireframe lines in logical pixels
+    pub line_width: f32,
+    // Samples per pixel for multisampling, 1 turns it off; usually 1 or 4
+    pub msaa: u32,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        RendererConfig {
+            vsync: Vsync::Auto,
+            clear_color: [0.0, 0.0, 0.0],
+            depth_fade: None,
+            line_width: 1.5,
+            msaa: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CameraConfig {
+    // Vertical field of view in degrees
+    pub fov: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        CameraConfig {
+            fov: 60.0,
+            near: 0.1,
+            far: 1000.0,
+        }
+    }
+}
+
+/// The `PEQUE_AUDIO` environment variable overrides the backend, headless runs always use the
+/// null one.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    // auto, rodio or null
+    pub backend: BackendKind,
+}
+
+/// The game logic runs at a fixed rate, independent of the frame rate.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationConfig {
+    // Simulation steps per second
+    pub hz: f32,
+    // Longest frame time simulated, in seconds; after a stall the game slows down instead
+    // of running many steps at once
+    pub max_frame_time: f32,
+    // Acceleration of the rigid bodies, y grows downwards on the screen
+    pub gravity: [f32; 3],
+    // Size of the cells of the spatial index used by collisions and queries
+    pub cell_size: f32,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        SimulationConfig {
+            hz: 60.0,
+            max_frame_time: 0.25,
+            gravity: [0.0, 9.81, 0.0],
+            cell_size: 10.0,
+        }
+    }
+}
+
+/// The Lua scripts of the game, `start` must define `start()` and is run once.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptsConfig {
+    pub start: String,
+    pub update: String,
+}
+
+impl Default for ScriptsConfig {
+    fn default() -> Self {
+        ScriptsConfig {
+            start: "scripting/start.lua".to_string(),
+            update: "scripting/update.lua".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_audio_backend_is_read_by_name() {
+        assert_eq!(GameConfig::default().audio.backend, BackendKind::Auto);
+        let config: GameConfig = toml::from_str("[audio]\nbackend = \"null\"").unwrap();
+        assert_eq!(config.audio.backend, BackendKind::Null);
+        assert!(toml::from_str::<GameConfig>("[audio]\nbackend = \"alsa\"").is_err());
+    }
+
+    #[test]
+    fn validate_rejects_values_the_engine_can_not_use() {
+        assert!(GameConfig::default().validate().is_ok());
+
+        let mut config = GameConfig::default();
+        config.window.height = 0;
+        assert!(config.validate().is_err());
+
+        for line_width in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            let mut config = GameConfig::default();
+            config.renderer.line_width = line_width;
+            assert!(config.validate().is_err(), "line width {}", line_width);
+        }
+
+        for fov in [0.0, -60.0, 180.0, f32::NAN] {
+            let mut config = GameConfig::default();
+            config.camera.fov = fov;
+            assert!(config.validate().is_err(), "fov {}", fov);
+        }
+
+        for (near, far) in [
+            (0.0, 10.0),
+            (-1.0, 10.0),
+            (f32::NAN, 10.0),
+            (5.0, 5.0),
+            (5.0, 1.0),
+        ] {
+            let mut config = GameConfig::default();
+            config.camera.near = near;
+            config.camera.far = far;
+            assert!(config.validate().is_err(), "near {} far {}", near, far);
+        }
+        let mut config = GameConfig::default();
+        config.camera.far = f32::INFINITY;
+        assert!(config.validate().is_err());
+
+        for cell_size in [0.0, -10.0, f32::NAN] {
+            let mut config = GameConfig::default();
+            config.simulation.cell_size = cell_size;
+            assert!(config.validate().is_err(), "cell size {}", cell_size);
+        }
+
+        for hz in [0.0, -60.0, f32::NAN, f32::INFINITY] {
+            let mut config = GameConfig::default();
+            config.simulation.hz = hz;
+            assert!(config.validate().is_err(), "hz {}", hz);
+        }
+
+        for max_frame_time in [0.0, -0.25, f32::NAN, f32::INFINITY, 0.01] {
+            let mut config = GameConfig::default();
+            config.simulation.max_frame_time = max_frame_time;
+            assert!(
+                config.validate().is_err(),
+                "max frame time {}",
+                max_frame_time
+            );
+        }
+    }
+}
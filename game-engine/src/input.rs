@@ -1,14 +1,50 @@
 use device_query::{DeviceQuery, DeviceState, Keycode};
 
+#[derive(Default)]
 pub struct Input {
     pub input: Vec<Keycode>,
     pub just_pressed: Vec<Keycode>,
     pub just_released: Vec<Keycode>,
+    // UTF-8 text typed since the last frame, already layout and IME aware
+    pub text: String,
+}
+
+impl Input {
+    /// Adds the inputs of a later frame, so none is lost when a frame runs no simulation step.
+    pub fn merge(&mut self, later: Input) {
+        self.input = later.input;
+        for key in later.just_pressed {
+            if !self.just_pressed.contains(&key) {
+                self.just_pressed.push(key);
+            }
+        }
+        for key in later.just_released {
+            if !self.just_released.contains(&key) {
+                self.just_released.push(key);
+            }
+        }
+        self.text.push_str(&later.text);
+    }
+
+    /// The same keys held down, without the events that must be seen only once.
+    pub fn held(&self) -> Input {
+        Input {
+            input: self.input.clone(),
+            ..Input::default()
+        }
+    }
 }
 
 pub struct InputSystem {
     device_state: DeviceState,
     last_time_input: Vec<Keycode>,
+    pending_text: String,
+}
+
+impl Default for InputSystem {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl InputSystem {
@@ -18,9 +54,15 @@ impl InputSystem {
         InputSystem {
             device_state,
             last_time_input,
+            pending_text: String::new(),
         }
     }
 
+    /// Queue text from the window events, it is handed out on the next `get_inputs`.
+    pub fn push_text(&mut self, text: &str) {
+        self.pending_text.push_str(text);
+    }
+
     pub fn get_inputs(&mut self) -> Input {
         let input: Vec<Keycode> = self.device_state.get_keys();
         let mut just_pressed = Vec::new();
@@ -42,6 +84,7 @@ impl InputSystem {
             input: input.clone(),
             just_pressed,
             just_released,
+            text: std::mem::take(&mut self.pending_text),
         }
     }
 }
@@ -0,0 +1,30 @@
+extern crate mlua;
+extern crate nalgebra as na;
+extern crate wgpu;
+extern crate winit;
+
+pub mod audio_backend;
+pub mod camera3d;
+pub mod capture;
+pub mod character;
+pub mod cli;
+pub mod collision;
+pub mod config;
+pub mod input;
+pub mod mixer;
+pub mod music;
+pub mod object3d;
+pub mod physics;
+pub mod query;
+pub mod rendering;
+pub mod replay;
+pub mod scripting;
+pub mod sound_buffer;
+pub mod sound_system;
+pub mod spatial;
+pub mod spatial_audio;
+pub mod synth;
+pub mod timestep;
+
+#[cfg(test)]
+mod test_util;
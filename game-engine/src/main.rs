@@ -1,46 +1,274 @@
-extern crate mlua;
-extern crate nalgebra as na;
-extern crate wgpu;
-extern crate winit;
-
-mod camera3d;
-mod input;
-mod object3d;
-mod rendering;
-mod scripting;
-mod sound_system;
-
-use camera3d::Camera3d;
-use input::InputSystem;
-use rendering::Renderer;
-use scripting::LuaInt;
+use mi_motor::audio_backend::BackendKind;
+use mi_motor::camera3d::Camera3d;
+use mi_motor::capture::{save_png, FrameCapture};
+use mi_motor::cli::{self, Command, RunOptions};
+use mi_motor::config::GameConfig;
+use mi_motor::input::{Input, InputSystem};
+use mi_motor::rendering::Renderer;
+use mi_motor::replay::{InputRecorder, InputReplay};
+use mi_motor::scripting::LuaInt;
+use mi_motor::sound_system::SoundSystem;
+use mi_motor::spatial_audio::Listener;
+use mi_motor::timestep::FixedTimestep;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use winit::{
     application::ApplicationHandler,
     dpi::PhysicalSize,
-    event::WindowEvent,
+    event::{ElementState, Ime, WindowEvent},
     event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
-    window::{WindowAttributes, WindowId},
+    keyboard::{KeyCode, PhysicalKey},
+    window::{Fullscreen, WindowAttributes, WindowId},
 };
 
-const CAMERA_SIZE_X: u32 = 1600;
-const CAMERA_SIZE_Y: u32 = 1200;
-const WINDOWS_TITLE: &str = "Rust 3D Motor";
+// Path to write the session inputs to
+const RECORD_ENV: &str = "PEQUE_RECORD";
+// Path of a recorded session to play back instead of the live inputs
+const REPLAY_ENV: &str = "PEQUE_REPLAY";
+// Audio backend: auto (default), rodio or null
+const AUDIO_ENV: &str = "PEQUE_AUDIO";
+// Folder to save every frame to, as numbered PNG images
+const CAPTURE_ENV: &str = "PEQUE_CAPTURE";
+// Frames per second of the capture, 60 by default
+const CAPTURE_FPS_ENV: &str = "PEQUE_CAPTURE_FPS";
+const SCREENSHOT_KEY: KeyCode = KeyCode::F12;
+// Headless runs have no display to sync to, they advance the game at 60 frames per second
+const HEADLESS_DT: f32 = 1.0 / 60.0;
 
 struct App {
+    config: GameConfig,
+    project: PathBuf,
     state: Option<Renderer>,
     last_time: std::time::Instant,
-    input_system: InputSystem,
+    // Frame time used instead of the real one, for captures and headless runs
+    fixed_dt: Option<f32>,
+    timestep: FixedTimestep,
+    // Inputs of the frames that ran no simulation step, given to the next step
+    pending_input: Option<Input>,
+    // Frames left before exiting, from --frames
+    frames_left: Option<u64>,
+    // Headless runs have no keyboard, their inputs are empty unless they play a replay
+    input_system: Option<InputSystem>,
+    // Between Ime::Enabled and Ime::Disabled the text comes from Ime::Commit only, key
+    // events may repeat it
+    ime_enabled: bool,
     lua: LuaInt,
+    recorder: Option<InputRecorder>,
+    replay: Option<InputReplay>,
+    capture: Option<FrameCapture>,
+    // Screenshots to save after the next draw
+    screenshots: Vec<String>,
 }
 
-impl Default for App {
-    fn default() -> Self {
+impl App {
+    fn new(options: &RunOptions, config: GameConfig) -> Self {
+        let replay = std::env::var(REPLAY_ENV).ok().map(|path| {
+            InputReplay::open(&path)
+                .unwrap_or_else(|e| panic!("Could not open the replay {}: {:?}", path, e))
+        });
+        let seed = match (&replay, options.seed) {
+            (Some(_), Some(_)) => {
+                panic!("--seed can not be used with a replay, it has its own seed")
+            }
+            (Some(replay), None) => replay.seed,
+            (None, Some(seed)) => seed,
+            (None, None) => rand::random(),
+        };
+        let recorder = std::env::var(RECORD_ENV).ok().map(|path| {
+            InputRecorder::create(&path, seed)
+                .unwrap_or_else(|e| panic!("Could not create the recording {}: {:?}", path, e))
+        });
+
+        let capture = std::env::var(CAPTURE_ENV).ok().map(|dir| {
+            let fps = match std::env::var(CAPTURE_FPS_ENV) {
+                Ok(fps) => fps
+                    .parse()
+                    .ok()
+                    .filter(|fps: &f32| fps.is_finite() && *fps > 0.0)
+                    .unwrap_or_else(|| panic!("Invalid capture fps: {}", fps)),
+                Err(_) => 60.0,
+            };
+            FrameCapture::new(&dir, fps)
+                .unwrap_or_else(|e| panic!("Could not create the capture folder {}: {:?}", dir, e))
+        });
+
+        let backend = match std::env::var(AUDIO_ENV) {
+            _ if options.headless => BackendKind::Null,
+            Ok(name) => BackendKind::from_name(&name)
+                .unwrap_or_else(|| panic!("Unknown audio backend: {}", name)),
+            Err(_) => config.audio.backend,
+        };
+        let sound_system = SoundSystem::new(backend)
+            .unwrap_or_else(|e| panic!("Could not start the {:?} audio backend: {}", backend, e));
+
+        let fixed_dt = match &capture {
+            // Captured videos play at a steady speed even if saving the frames is slow
+            Some(capture) => Some(capture.dt),
+            None if options.headless => Some(HEADLESS_DT),
+            None => None,
+        };
+        let input_system = if options.headless {
+            None
+        } else {
+            Some(InputSystem::new())
+        };
+
+        let lua = LuaInt::new(seed, sound_system, &options.project, &config.scripts).unwrap();
+        lua.set_gravity(config.simulation.gravity.into());
+        lua.set_cell_size(config.simulation.cell_size);
+        let timestep = FixedTimestep::new(config.simulation.hz, config.simulation.max_frame_time);
         Self {
+            config,
+            project: options.project.clone(),
             state: None,
             last_time: std::time::Instant::now(),
-            input_system: InputSystem::new(),
-            lua: LuaInt::new().unwrap(),
+            fixed_dt,
+            timestep,
+            pending_input: None,
+            frames_left: options.frames,
+            input_system,
+            ime_enabled: false,
+            lua,
+            recorder,
+            replay,
+            capture,
+            screenshots: Vec::new(),
+        }
+    }
+
+    /// The camera of the config, the renderer sets its aspect from the size of its surface.
+    fn camera(&self) -> Camera3d {
+        let camera_config = &self.config.camera;
+        Camera3d::new(
+            camera_config.fov,
+            1.0,
+            camera_config.near,
+            camera_config.far,
+        )
+    }
+
+    /// Returns the dt and inputs of this frame, from the replay if there is one.
+    /// `None` means the replay has finished or the --frames limit was reached.
+    fn next_frame(&mut self) -> Option<(f32, Input)> {
+        if let Some(frames_left) = self.frames_left.as_mut() {
+            if *frames_left == 0 {
+                return None;
+            }
+            *frames_left -= 1;
+        }
+
+        let now = std::time::Instant::now();
+        let dt = now.duration_since(self.last_time).as_secs_f32();
+        self.last_time = now;
+        let dt = self.fixed_dt.unwrap_or(dt);
+
+        let frame = match self.replay.as_mut() {
+            Some(replay) => replay.next_frame()?,
+            None => match self.input_system.as_mut() {
+                Some(input_system) => (dt, input_system.get_inputs()),
+                None => (dt, Input::default()),
+            },
+        };
+
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.record(frame.0, &frame.1) {
+                eprintln!("Error grabando inputs: {:?}", e);
+                self.recorder = None;
+            }
+        }
+        Some(frame)
+    }
+
+    /// Runs one frame of the game, this is like the main loop.
+    /// The scripts run as many fixed steps as fit in the frame time, then the objects are drawn
+    /// between their last two steps. Returns false when the game has to exit.
+    fn step(&mut self) -> bool {
+        let Some((dt, inputs)) = self.next_frame() else {
+            return false;
+        };
+        let mut inputs = match self.pending_input.take() {
+            Some(mut pending) => {
+                pending.merge(inputs);
+                pending
+            }
+            None => inputs,
+        };
+        if let Some(state) = self.state.as_ref() {
+            self.lua.set_listener(Listener {
+                position: state.camera.position,
+                right: state.camera.right(),
+            });
+        }
+        let steps = self.timestep.advance(dt);
+        for _ in 0..steps {
+            if let Err(e) = self.lua.update(self.timestep.step, &inputs) {
+                eprintln!("Error en scripting: {:?}", e);
+            }
+            // Presses, releases and text are only seen by the first step
+            inputs = inputs.held();
+        }
+        if steps == 0 {
+            self.pending_input = Some(inputs);
+        }
+
+        self.screenshots
+            .append(&mut self.lua.screenshots.borrow_mut());
+
+        let state = self.state.as_mut().unwrap();
+        if !self.screenshots.is_empty() || self.capture.is_some() {
+            state.request_capture();
+        }
+        let alpha = self.timestep.alpha();
+        let drawn = state.draw(
+            &mut self.lua.objects.borrow_mut(),
+            &self.lua.spatial(),
+            alpha,
+        );
+        if let Err(e) = drawn {
+            eprintln!("Error en render: {:?}", e);
+        }
+        self.lua.render_stats.set(state.stats());
+        self.save_captures();
+        true
+    }
+
+    /// Runs the game without window until it exits, see `step`.
+    fn run_headless(&mut self) {
+        let window_config = &self.config.window;
+        let state = pollster::block_on(Renderer::new_headless(
+            window_config.width,
+            window_config.height,
+            self.camera(),
+            &self.config.renderer,
+        ))
+        .unwrap_or_else(|e| panic!("Could not create the headless renderer: {}", e));
+        self.state = Some(state);
+        while self.step() {}
+    }
+
+    /// Saves the frame copied by the renderer to the pending screenshots and the capture folder.
+    fn save_captures(&mut self) {
+        let Some(state) = self.state.as_mut() else {
+            return;
+        };
+        let screenshots = std::mem::take(&mut self.screenshots);
+        if screenshots.is_empty() && self.capture.is_none() {
+            return;
+        }
+        let Some(pixels) = state.take_capture() else {
+            return;
+        };
+        let (width, height) = (state.size.width, state.size.height);
+        for path in screenshots {
+            match save_png(Path::new(&path), width, height, &pixels) {
+                Ok(()) => println!("Captura guardada en {}", path),
+                Err(e) => eprintln!("Error guardando la captura {}: {}", path, e),
+            }
+        }
+        if let Some(capture) = self.capture.as_mut() {
+            if let Err(e) = capture.save(width, height, &pixels) {
+                eprintln!("Error guardando el frame: {}", e);
+            }
         }
     }
 }
@@ -48,54 +276,74 @@ impl Default for App {
 impl ApplicationHandler for App {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         // Initialize everything here
+        let window_config = &self.config.window;
         let mut attrs = WindowAttributes::default();
-        attrs.title = WINDOWS_TITLE.into();
+        attrs.title = window_config.title.clone();
         attrs.inner_size = Some(winit::dpi::Size::Physical(PhysicalSize::new(
-            CAMERA_SIZE_X,
-            CAMERA_SIZE_Y,
+            window_config.width,
+            window_config.height,
         )));
+        if window_config.fullscreen {
+            attrs.fullscreen = Some(Fullscreen::Borderless(None));
+        }
         let window = Arc::new(event_loop.create_window(attrs).unwrap());
-
-        let camera = Camera3d::new(
-            60.0,
-            (CAMERA_SIZE_X as f32) / (CAMERA_SIZE_Y as f32),
-            0.1,
-            1000.0,
-        );
+        // Needed to receive composed text (accents, CJK...) as Ime::Commit events
+        window.set_ime_allowed(true);
 
         let state = pollster::block_on(Renderer::new(
             window.clone(),
-            CAMERA_SIZE_X,
-            CAMERA_SIZE_Y,
-            camera,
+            window_config.width,
+            window_config.height,
+            self.camera(),
+            &self.config.renderer,
         ));
         self.state = Some(state);
         window.request_redraw();
     }
 
     fn window_event(&mut self, event_loop: &ActiveEventLoop, _id: WindowId, event: WindowEvent) {
-        if let Some(state) = self.state.as_mut() {
+        if self.state.is_some() {
             match event {
                 WindowEvent::CloseRequested => {
                     event_loop.exit();
                 }
                 WindowEvent::RedrawRequested => {
-                    // This is like the main loop
-                    let now = std::time::Instant::now();
-                    let dt = now.duration_since(self.last_time).as_secs_f32();
-                    self.last_time = now;
-
-                    let inputs = self.input_system.get_inputs();
-                    if let Err(e) = self.lua.update(dt, &inputs) {
-                        eprintln!("Error en scripting: {:?}", e);
+                    if !self.step() {
+                        event_loop.exit();
+                        return;
                     }
-
-                    if let Err(e) = state.draw(&mut self.lua.objects.borrow_mut()) {
-                        eprintln!("Error en render: {:?}", e);
+                    let state = self.state.as_ref().unwrap();
+                    if let Some(window) = state.get_window() {
+                        window.request_redraw();
                     }
-                    state.get_window().request_redraw();
                 }
-                WindowEvent::Resized(size) => state.resize(size),
+                WindowEvent::KeyboardInput { event, .. }
+                    if event.state == ElementState::Pressed =>
+                {
+                    if event.physical_key == PhysicalKey::Code(SCREENSHOT_KEY) && !event.repeat {
+                        let time = std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .unwrap_or_default();
+                        let path = self
+                            .project
+                            .join(format!("screenshot_{}.png", time.as_millis()));
+                        self.screenshots.push(path.to_string_lossy().into_owned());
+                    }
+                    let input_system = self.input_system.as_mut();
+                    if let (Some(text), Some(input_system)) = (event.text, input_system) {
+                        if !self.ime_enabled {
+                            input_system.push_text(&text);
+                        }
+                    }
+                }
+                WindowEvent::Ime(Ime::Enabled) => self.ime_enabled = true,
+                WindowEvent::Ime(Ime::Disabled) => self.ime_enabled = false,
+                WindowEvent::Ime(Ime::Commit(text)) => {
+                    if let Some(input_system) = self.input_system.as_mut() {
+                        input_system.push_text(&text);
+                    }
+                }
+                WindowEvent::Resized(size) => self.state.as_mut().unwrap().resize(size),
                 _ => {}
             }
         }
@@ -105,9 +353,39 @@ impl ApplicationHandler for App {
 fn main() {
     env_logger::init();
 
+    let options = match cli::parse(std::env::args().skip(1)) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Help) => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let config_path = options.config_path();
+    // Projects without a config use the defaults, but a config given with --config must exist
+    let config = if config_path.exists() || options.config.is_some() {
+        match GameConfig::load(&config_path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        GameConfig::default()
+    };
+
+    let mut app = App::new(&options, config);
+    if options.headless {
+        app.run_headless();
+        return;
+    }
+
     let event_loop = EventLoop::new().unwrap();
     event_loop.set_control_flow(ControlFlow::Poll);
-
-    let mut app = App::default();
     event_loop.run_app(&mut app).unwrap();
 }
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+pub const MASTER_BUS: &str = "master";
+pub const SFX_BUS: &str = "sfx";
+pub const MUSIC_BUS: &str = "music";
+pub const UI_BUS: &str = "ui";
+
+struct Bus {
+    volume: f32,
+    muted: bool,
+}
+
+/// Named volume groups, every sound plays through one of them and through the master bus.
+/// Buses other than the default ones are created the first time they are used.
+pub struct Mixer {
+    buses: HashMap<String, Bus>,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        let mut buses = HashMap::new();
+        for name in [MASTER_BUS, SFX_BUS, MUSIC_BUS, UI_BUS] {
+            buses.insert(
+                name.to_string(),
+                Bus {
+                    volume: 1.0,
+                    muted: false,
+                },
+            );
+        }
+        Mixer { buses }
+    }
+
+    fn bus_mut(&mut self, name: &str) -> &mut Bus {
+        self.buses.entry(name.to_string()).or_insert(Bus {
+            volume: 1.0,
+            muted: false,
+        })
+    }
+
+    pub fn set_volume(&mut self, name: &str, volume: f32) {
+        self.bus_mut(name).volume = volume.max(0.0);
+    }
+
+    pub fn volume(&self, name: &str) -> f32 {
+        self.buses.get(name).map_or(1.0, |bus| bus.volume)
+    }
+
+    pub fn set_muted(&mut self, name: &str, muted: bool) {
+        self.bus_mut(name).muted = muted;
+    }
+
+    pub fn is_muted(&self, name: &str) -> bool {
+        self.buses.get(name).is_some_and(|bus| bus.muted)
+    }
+
+    /// The volume a sound of the bus is played at, including the master bus.
+    pub fn gain(&self, name: &str) -> f32 {
+        let gain_of = |name: &str| {
+            if self.is_muted(name) {
+                0.0
+            } else {
+                self.volume(name)
+            }
+        };
+        if name == MASTER_BUS {
+            gain_of(MASTER_BUS)
+        } else {
+            gain_of(MASTER_BUS) * gain_of(name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gains_multiply_the_bus_by_the_master() {
+        let mut mixer = Mixer::new();
+        assert_eq!(mixer.gain(SFX_BUS), 1.0);
+        mixer.set_volume(MASTER_BUS, 0.5);
+        mixer.set_volume(SFX_BUS, 0.4);
+        assert_eq!(mixer.gain(SFX_BUS), 0.2);
+        assert_eq!(mixer.gain(MUSIC_BUS), 0.5);
+        assert_eq!(mixer.gain(MASTER_BUS), 0.5);
+
+        // Buses nobody set play at full volume, under the master
+        assert_eq!(mixer.gain("voices"), 0.5);
+        mixer.set_volume("voices", -1.0);
+        assert_eq!(mixer.volume("voices"), 0.0);
+    }
+
+    #[test]
+    fn muting_silences_without_losing_the_volume() {
+        let mut mixer = Mixer::new();
+        mixer.set_volume(MUSIC_BUS, 0.8);
+        mixer.set_muted(MUSIC_BUS, true);
+        assert_eq!(mixer.gain(MUSIC_BUS), 0.0);
+        assert_eq!(mixer.gain(SFX_BUS), 1.0);
+        mixer.set_muted(MUSIC_BUS, false);
+        assert_eq!(mixer.gain(MUSIC_BUS), 0.8);
+
+        mixer.set_muted(MASTER_BUS, true);
+        assert_eq!(mixer.gain(SFX_BUS), 0.0);
+        assert_eq!(mixer.gain(MUSIC_BUS), 0.0);
+        assert_eq!(mixer.volume(MASTER_BUS), 1.0);
+    }
+}
@@ -0,0 +1,233 @@
+use rodio::{Decoder, Sink};
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::audio_backend::{AudioBackend, PlayKind, PlayedSound};
+use crate::mixer::MUSIC_BUS;
+use crate::sound_system::SoundError;
+
+/// A track of the music channel, decoded from disk while it plays.
+struct MusicTrack {
+    path: String,
+    // None on the silent sound system
+    sink: Option<Sink>,
+    looping: bool,
+    // Fade level between 0 and 1, multiplied by the channel volume
+    level: f32,
+    target: f32,
+    // Level change per second, always positive
+    fade_speed: f32,
+}
+
+impl MusicTrack {
+    fn open(path: &str, backend: &dyn AudioBackend) -> Result<Option<Sink>, SoundError> {
+        let file =
+            File::open(path).map_err(|e| format!("Could not open the music {}: {}", path, e))?;
+        let Some(sink) = backend.new_sink()? else {
+            return Ok(None);
+        };
+        let decoder = Decoder::new(BufReader::new(file))
+            .map_err(|e| format!("Could not decode the music {}: {}", path, e))?;
+        // Silent until the first update applies the fade
+        sink.set_volume(0.0);
+        sink.append(decoder);
+        Ok(Some(sink))
+    }
+
+    fn fade_to(&mut self, target: f32, seconds: f32) {
+        self.target = target;
+        self.fade_speed = if seconds > 0.0 {
+            1.0 / seconds
+        } else {
+            f32::INFINITY
+        };
+    }
+
+    /// Moves the fade towards its target and restarts looping tracks that reached the end.
+    fn update(&mut self, dt: f32, volume: f32, backend: &dyn AudioBackend) {
+        let step = self.fade_speed * dt;
+        if self.level < self.target {
+            self.level = (self.level + step).min(self.target);
+        } else {
+            self.level = (self.level - step).max(self.target);
+        }
+
+        let finished = self.sink.as_ref().is_some_and(|sink| sink.empty());
+        if finished && self.looping {
+            match Self::open(&self.path, backend) {
+                Ok(sink) => self.sink = sink,
+                Err(e) => {
+                    eprintln!("Error en musica: {}", e);
+                    self.looping = false;
+                }
+            }
+        }
+
+        if let Some(sink) = &self.sink {
+            sink.set_volume(self.level * volume);
+        }
+    }
+
+    fn is_silent(&self) -> bool {
+        self.level <= 0.0 && self.target <= 0.0
+    }
+}
+
+/// Background music, streamed from disk instead of being cached like the sound effects.
+/// Only one track plays at a time, the previous one fades out during a crossfade.
+pub struct MusicChannel {
+    current: Option<MusicTrack>,
+    fading_out: Vec<MusicTrack>,
+}
+
+impl Default for MusicChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MusicChannel {
+    pub fn new() -> Self {
+        MusicChannel {
+            current: None,
+            fading_out: Vec::new(),
+        }
+    }
+
+    /// Starts a track, crossfading from the current one during `fade` seconds.
+    pub fn play(
+        &mut self,
+        path: &str,
+        looping: bool,
+        fade: f32,
+        backend: &dyn AudioBackend,
+    ) -> Result<(), SoundError> {
+        let sink = MusicTrack::open(path, backend)?;
+        backend.record(&PlayedSound {
+            kind: PlayKind::Music,
+            path: path.to_owned(),
+            bus: MUSIC_BUS.to_string(),
+            volume: 1.0,
+            looping,
+            positional: false,
+        });
+        self.stop(fade);
+
+        let mut track = MusicTrack {
+            path: path.to_owned(),
+            sink,
+            looping,
+            level: 0.0,
+            target: 1.0,
+            fade_speed: 0.0,
+        };
+        track.fade_to(1.0, fade);
+        self.current = Some(track);
+        Ok(())
+    }
+
+    /// Fades out the current track during `fade` seconds.
+    pub fn stop(&mut self, fade: f32) {
+        if let Some(mut track) = self.current.take() {
+            track.looping = false;
+            track.fade_to(0.0, fade);
+            self.fading_out.push(track);
+        }
+    }
+
+    /// Advances the fades, `volume` is the gain of the music bus.
+    pub fn update(&mut self, dt: f32, volume: f32, backend: &dyn AudioBackend) {
+        if let Some(track) = self.current.as_mut() {
+            track.update(dt, volume, backend);
+        }
+        for track in self.fading_out.iter_mut() {
+            track.update(dt, volume, backend);
+        }
+        // Dropping the sink stops the stream
+        self.fading_out.retain(|track| !track.is_silent());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_backend::NullBackend;
+
+    const BLASTER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/audio/blaster.wav");
+
+    // Advances the backend and the fades like the sound system does each update
+    fn update(channel: &mut MusicChannel, backend: &NullBackend, dt: f32, volume: f32) {
+        backend.advance(dt);
+        channel.update(dt, volume, backend);
+    }
+
+    fn sink_volume(track: &MusicTrack) -> f32 {
+        track.sink.as_ref().unwrap().volume()
+    }
+
+    fn assert_near(found: f32, expected: f32) {
+        assert!((found - expected).abs() < 1e-5, "{} != {}", found, expected);
+    }
+
+    #[test]
+    fn crossfades_ramp_one_track_down_and_the_other_up() {
+        let backend = NullBackend::new();
+        let mut channel = MusicChannel::new();
+        channel.play(BLASTER, true, 0.0, &backend).unwrap();
+        update(&mut channel, &backend, 0.01, 0.5);
+        assert_near(sink_volume(channel.current.as_ref().unwrap()), 0.5);
+
+        channel.play(BLASTER, true, 1.0, &backend).unwrap();
+        for step in 1..=3 {
+            update(&mut channel, &backend, 0.25, 0.5);
+            let level = step as f32 * 0.25;
+            let current = channel.current.as_ref().unwrap();
+            assert_near(current.level, level);
+            assert_near(sink_volume(current), level * 0.5);
+            assert_eq!(channel.fading_out.len(), 1);
+            assert_near(channel.fading_out[0].level, 1.0 - level);
+            assert_near(sink_volume(&channel.fading_out[0]), (1.0 - level) * 0.5);
+        }
+        update(&mut channel, &backend, 0.25, 0.5);
+        assert_near(channel.current.as_ref().unwrap().level, 1.0);
+        assert!(channel.fading_out.is_empty());
+    }
+
+    #[test]
+    fn stopped_tracks_fade_out_and_are_dropped() {
+        let backend = NullBackend::new();
+        let mut channel = MusicChannel::new();
+        channel.play(BLASTER, true, 0.0, &backend).unwrap();
+        update(&mut channel, &backend, 0.01, 1.0);
+
+        channel.stop(0.5);
+        assert!(channel.current.is_none());
+        update(&mut channel, &backend, 0.25, 1.0);
+        assert_near(channel.fading_out[0].level, 0.5);
+        assert!(!channel.fading_out[0].looping);
+        update(&mut channel, &backend, 0.25, 1.0);
+        assert!(channel.fading_out.is_empty());
+
+        channel.play(BLASTER, false, 0.0, &backend).unwrap();
+        channel.stop(0.0);
+        update(&mut channel, &backend, 0.01, 1.0);
+        assert!(channel.current.is_none() && channel.fading_out.is_empty());
+    }
+
+    #[test]
+    fn looping_tracks_start_again_when_they_end() {
+        let backend = NullBackend::new();
+        let mut channel = MusicChannel::new();
+        channel.play(BLASTER, true, 0.0, &backend).unwrap();
+        let mut once = MusicChannel::new();
+        once.play(BLASTER, false, 0.0, &backend).unwrap();
+        for _ in 0..100 {
+            update(&mut channel, &backend, 0.1, 1.0);
+            once.update(0.1, 1.0, &backend);
+        }
+        let looping = channel.current.as_ref().unwrap();
+        assert!(!looping.sink.as_ref().unwrap().empty());
+        let played_once = once.current.as_ref().unwrap();
+        assert!(played_once.sink.as_ref().unwrap().empty());
+    }
+}
@@ -1,59 +1,227 @@
 use na::{Matrix3, Vector3};
-use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::PI;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::collision::{Bounds, Collider, ColliderKind, CollisionFilter, WorldShape};
+use crate::physics::RigidBody;
+
+/// Red, green, blue and alpha from 0 to 1.
+pub type Rgba = [f32; 4];
+
+/// What is read from an OBJ file.
+pub struct ObjModel {
+    pub points: Vec<Vector3<f32>>,
+    // Indices of the points around each face
+    pub faces: Vec<Vec<usize>>,
+    // Color of the material of each face
+    pub face_colors: Vec<Option<Rgba>>,
+    // Outline of the faces, each shared edge once
+    pub edges: Vec<Edge>,
+}
+
+/// A line of the wireframe between two points of the model.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edge {
+    pub a: usize,
+    pub b: usize,
+    // First face around the edge, the line takes its color
+    pub face: usize,
+}
 
 #[derive(Clone, Debug)]
 #[allow(dead_code)]
 pub struct Object3d {
     pub object_name: String,
+    // Unique id, scripts see it as `object.id`
     pub random_id: u32,
     model: Vec<Vector3<f32>>,
-    pub edges: Vec<Vec<usize>>,
+    // Box around the mesh in model space
+    model_bounds: Bounds,
+    // Sphere around the mesh in model space, centered on the box; used to cull it
+    bounding_center: Vector3<f32>,
+    bounding_radius: f32,
+    // Indices of the points around each face
+    pub faces: Vec<Vec<usize>>,
+    pub edges: Vec<Edge>,
+    // Color of each face, from the material of the model when it has one
+    pub face_colors: Vec<Option<Rgba>>,
+    // Tints the colors of the faces
+    pub color: Rgba,
     pub position: Vector3<f32>,
     pub rotation: f32,
     pub render: bool,
+    pub collider: Option<Collider>,
+    pub filter: CollisionFilter,
+    // Triggers report what enters and exits them but nothing bumps into them
+    pub trigger: bool,
+    pub body: Option<RigidBody>,
+    // Transform before the last simulation step, drawing blends it with the current one
+    previous_position: Vector3<f32>,
+    previous_rotation: f32,
     transformed_cache: Vec<Vector3<f32>>,
 }
 
 impl Object3d {
-    /// Create a new Object3d from a model path, position and rotation.
-    pub fn new(model_path: &str, position: Vector3<f32>, rotation: f32) -> Self {
-        let num = rand::thread_rng().gen_range(0..100000);
-        let (model, edges) = Self::load_obj(model_path);
+    /// Create a new Object3d from its id, a model path, position and rotation. The id must be
+    /// unique, the scripting context counts them up in creation order.
+    pub fn new(id: u32, model_path: &str, position: Vector3<f32>, rotation: f32) -> Self {
+        let ObjModel {
+            points: model,
+            faces,
+            face_colors,
+            edges,
+        } = Self::load_obj(model_path);
+        let model_bounds = Bounds::of_points(model.iter().copied());
+        let bounding_center = (model_bounds.min + model_bounds.max) / 2.0;
+        let bounding_radius = model
+            .iter()
+            .map(|p| (p - bounding_center).norm())
+            .fold(0.0, f32::max);
         let mut transformed_cache = Vec::with_capacity(model.len());
         transformed_cache.resize(model.len(), Vector3::zeros());
         Object3d {
             object_name: model_path.to_string(),
-            random_id: num,
+            random_id: id,
+            model_bounds,
+            bounding_center,
+            bounding_radius,
             model,
+            faces,
             edges,
+            face_colors,
+            color: [1.0, 1.0, 1.0, 1.0],
             position,
             rotation,
+            previous_position: position,
+            previous_rotation: rotation,
             transformed_cache,
             render: true,
+            collider: None,
+            filter: CollisionFilter::default(),
+            trigger: false,
+            body: None,
         }
     }
 
+    /// Rotation matrix of a yaw angle, around the y axis.
+    pub fn yaw_matrix(rotation: f32) -> Matrix3<f32> {
+        let cos_theta = rotation.cos();
+        let sin_theta = rotation.sin();
+        Matrix3::new(
+            cos_theta, 0.0, sin_theta, 0.0, 1.0, 0.0, -sin_theta, 0.0, cos_theta,
+        )
+    }
+
+    /// Fits a collider of the kind to the mesh, `None` removes the collider.
+    pub fn set_collider(&mut self, kind: Option<ColliderKind>) {
+        self.collider = kind.map(|kind| Collider::fit(kind, &self.model));
+    }
+
+    /// The collider placed at the current transform.
+    pub fn collision_shape(&self) -> Option<WorldShape> {
+        self.collider
+            .as_ref()
+            .map(|collider| collider.world_shape(&self.position, &Self::yaw_matrix(self.rotation)))
+    }
+
+    /// Box around the mesh and the collider at the current transform.
+    pub fn bounds(&self) -> Bounds {
+        let rotation_matrix = Self::yaw_matrix(self.rotation);
+        let (min, max) = (self.model_bounds.min, self.model_bounds.max);
+        let corners = (0..8).map(|i| {
+            let corner = Vector3::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            );
+            rotation_matrix * corner + self.position
+        });
+        let bounds = Bounds::of_points(corners);
+        match self.collision_shape() {
+            Some(shape) => bounds.union(&shape.bounds()),
+            None => bounds,
+        }
+    }
+
+    /// The points of the mesh at the current transform, without touching the draw cache.
+    pub fn world_points(&self) -> Vec<Vector3<f32>> {
+        let rotation_matrix = Self::yaw_matrix(self.rotation);
+        self.model
+            .iter()
+            .map(|p| rotation_matrix * p + self.position)
+            .collect()
+    }
+
+    /// Remember the current transform, called before every simulation step.
+    pub fn save_previous_transform(&mut self) {
+        self.previous_position = self.position;
+        self.previous_rotation = self.rotation;
+    }
+
     /// Apply a translation to the object.
     pub fn transform_points(&mut self) -> &[Vector3<f32>] {
-        let cos_theta = self.rotation.cos();
-        let sin_theta = self.rotation.sin();
-        let rotation_matrix = Matrix3::new(
-            cos_theta, 0.0, sin_theta, 0.0, 1.0, 0.0, -sin_theta, 0.0, cos_theta,
-        );
+        self.transform_points_at(1.0)
+    }
+
+    /// Position and rotation `alpha` of the way from the previous simulation step to the
+    /// current one.
+    fn transform_at(&self, alpha: f32) -> (Vector3<f32>, f32) {
+        let position = self.previous_position.lerp(&self.position, alpha);
+        // Turn the shortest way, so going from 359 to 1 degree does not spin the whole circle
+        let turn = (self.rotation - self.previous_rotation + PI).rem_euclid(2.0 * PI) - PI;
+        (position, self.previous_rotation + turn * alpha)
+    }
+
+    /// Center and radius of a sphere around the mesh at the transform `transform_points_at`
+    /// draws with `alpha`.
+    pub fn bounding_sphere_at(&self, alpha: f32) -> (Vector3<f32>, f32) {
+        let (position, rotation) = self.transform_at(alpha);
+        let center = Self::yaw_matrix(rotation) * self.bounding_center + position;
+        (center, self.bounding_radius)
+    }
+
+    /// Like `transform_points`, but with the transform `alpha` of the way from the previous
+    /// simulation step to the current one.
+    pub fn transform_points_at(&mut self, alpha: f32) -> &[Vector3<f32>] {
+        let (position, rotation) = self.transform_at(alpha);
+        let rotation_matrix = Self::yaw_matrix(rotation);
 
         for (i, p) in self.model.iter().enumerate() {
-            self.transformed_cache[i] = rotation_matrix * p + self.position;
+            self.transformed_cache[i] = rotation_matrix * p + position;
         }
         &self.transformed_cache
     }
 
-    pub fn load_obj(file_path: &str) -> (Vec<Vector3<f32>>, Vec<Vec<usize>>) {
+    /// Color of a face: the color of its material tinted by the color of the object.
+    pub fn face_color(&self, face: usize) -> Rgba {
+        let base = self
+            .face_colors
+            .get(face)
+            .copied()
+            .flatten()
+            .unwrap_or([1.0; 4]);
+        [
+            base[0] * self.color[0],
+            base[1] * self.color[1],
+            base[2] * self.color[2],
+            base[3] * self.color[3],
+        ]
+    }
+
+    /// Reads the points and faces of an OBJ file, and the color of each face from the diffuse
+    /// color of its material. Faces without material, or whose material library is missing,
+    /// have no color.
+    pub fn load_obj(file_path: &str) -> ObjModel {
         let file = File::open(file_path).expect("Could not open the file");
         let reader = BufReader::new(file);
         let mut vertices = Vec::new();
-        let mut edges = Vec::new();
+        let mut faces = Vec::new();
+        let mut face_colors = Vec::new();
+        let mut materials = HashMap::new();
+        let mut current_color = None;
 
         for line in reader.lines() {
             let line = line.expect("Error reading line");
@@ -73,9 +241,137 @@ impl Object3d {
                     let index: usize = index_str.parse().unwrap_or(0);
                     face.push(index - 1);
                 }
-                edges.push(face);
+                faces.push(face);
+                face_colors.push(current_color);
+            } else if let Some(library) = line.strip_prefix("mtllib ") {
+                // Relative to the folder of the model
+                let path = Path::new(file_path)
+                    .parent()
+                    .unwrap_or(Path::new(""))
+                    .join(library.trim());
+                materials.extend(Self::load_mtl(&path));
+            } else if let Some(name) = line.strip_prefix("usemtl ") {
+                current_color = materials.get(name.trim()).copied();
+            }
+        }
+        let edges = Self::unique_edges(&faces);
+        ObjModel {
+            points: vertices,
+            faces,
+            face_colors,
+            edges,
+        }
+    }
+
+    /// Edges around every face, closing each loop from its last point back to the first.
+    /// An edge shared by neighboring faces is listed once, for the first of them.
+    pub fn unique_edges(faces: &[Vec<usize>]) -> Vec<Edge> {
+        let mut seen = HashSet::new();
+        let mut edges = Vec::new();
+        for (face, points) in faces.iter().enumerate() {
+            for (i, &a) in points.iter().enumerate() {
+                let b = points[(i + 1) % points.len()];
+                if a != b && seen.insert((a.min(b), a.max(b))) {
+                    edges.push(Edge { a, b, face });
+                }
+            }
+        }
+        edges
+    }
+
+    /// Diffuse color and opacity of each material of an MTL file, nothing when it is missing.
+    fn load_mtl(path: &Path) -> HashMap<String, Rgba> {
+        let mut materials = HashMap::new();
+        let Ok(file) = File::open(path) else {
+            return materials;
+        };
+        let mut current: Option<String> = None;
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let value = |i: usize| parts.get(i).and_then(|v| v.parse::<f32>().ok());
+            match parts.first() {
+                Some(&"newmtl") => {
+                    let name = parts[1..].join(" ");
+                    materials.insert(name.clone(), [1.0; 4]);
+                    current = Some(name);
+                }
+                Some(&"Kd") => {
+                    if let (Some(color), Some(r), Some(g), Some(b)) = (
+                        current.as_ref().and_then(|name| materials.get_mut(name)),
+                        value(1),
+                        value(2),
+                        value(3),
+                    ) {
+                        color[0] = r;
+                        color[1] = g;
+                        color[2] = b;
+                    }
+                }
+                // Dissolve is the opacity, Tr its opposite
+                Some(&"d") | Some(&"Tr") => {
+                    if let (Some(color), Some(v)) = (
+                        current.as_ref().and_then(|name| materials.get_mut(name)),
+                        value(1),
+                    ) {
+                        color[3] = if parts[0] == "d" { v } else { 1.0 - v };
+                    }
+                }
+                _ => {}
+            }
+        }
+        materials
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::cube_at;
+
+    #[test]
+    fn shared_edges_are_listed_once_for_the_first_face() {
+        // Two squares side by side, sharing the edge between 1 and 2
+        let faces = vec![vec![0, 1, 2, 3], vec![1, 4, 5, 2]];
+        let edges = Object3d::unique_edges(&faces);
+        assert_eq!(edges.len(), 7);
+        assert!(edges.contains(&Edge {
+            a: 3,
+            b: 0,
+            face: 0
+        }));
+        assert!(edges.contains(&Edge {
+            a: 1,
+            b: 2,
+            face: 0
+        }));
+        assert!(!edges.iter().any(|edge| edge.a == 2 && edge.b == 1));
+    }
+
+    #[test]
+    fn the_cube_has_its_edges_and_face_diagonals() {
+        let cube = cube_at(1, Vector3::zeros());
+        // 12 triangles: the 12 edges of the cube and one diagonal on each of its 6 sides
+        assert_eq!(cube.faces.len(), 12);
+        assert_eq!(cube.edges.len(), 18);
+        let mut pairs: Vec<(usize, usize)> = cube
+            .edges
+            .iter()
+            .map(|edge| (edge.a.min(edge.b), edge.a.max(edge.b)))
+            .collect();
+        pairs.sort_unstable();
+        pairs.dedup();
+        assert_eq!(pairs.len(), 18);
+        for (i, face) in cube.faces.iter().enumerate() {
+            for (j, &a) in face.iter().enumerate() {
+                let b = face[(j + 1) % face.len()];
+                assert!(
+                    pairs.contains(&(a.min(b), a.max(b))),
+                    "face {} misses {}-{}",
+                    i,
+                    a,
+                    b
+                );
             }
         }
-        (vertices, edges)
     }
 }
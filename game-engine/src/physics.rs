@@ -0,0 +1,341 @@
+use na::Vector3;
+
+use crate::collision::Contact;
+use crate::object3d::Object3d;
+
+// Fraction of the overlap removed each step, less than 1 so resting objects do not jitter
+const POSITION_CORRECTION: f32 = 0.8;
+// Overlap allowed before correcting it, so resting contacts stay touching
+const PENETRATION_SLOP: f32 = 0.01;
+
+/// Makes an object move with velocity, forces and collisions. Objects with a collider but
+/// without body are static, bodies push each other and bounce off them.
+#[derive(Clone, Debug)]
+pub struct RigidBody {
+    pub velocity: Vector3<f32>,
+    // Radians per second around the y axis, the only rotation objects have
+    pub angular_velocity: f32,
+    // Kilograms, 0 makes the body kinematic: it moves with its velocity but forces and
+    // collisions do not change it
+    pub mass: f32,
+    // 1 is the gravity of the world, 0 makes it float
+    pub gravity_scale: f32,
+    // Fraction of the velocity lost per second
+    pub linear_damping: f32,
+    pub angular_damping: f32,
+    // 0 stops on impact, 1 bounces without losing speed
+    pub restitution: f32,
+    pub friction: f32,
+    // Forces applied since the last step, cleared after each step
+    force: Vector3<f32>,
+    torque: f32,
+}
+
+impl Default for RigidBody {
+    fn default() -> Self {
+        RigidBody {
+            velocity: Vector3::zeros(),
+            angular_velocity: 0.0,
+            mass: 1.0,
+            gravity_scale: 1.0,
+            linear_damping: 0.0,
+            angular_damping: 0.0,
+            restitution: 0.2,
+            friction: 0.5,
+            force: Vector3::zeros(),
+            torque: 0.0,
+        }
+    }
+}
+
+impl RigidBody {
+    pub fn inverse_mass(&self) -> f32 {
+        if self.mass > 0.0 {
+            1.0 / self.mass
+        } else {
+            0.0
+        }
+    }
+
+    /// Pushes the body during the next step, call it every step for a continuous force.
+    pub fn apply_force(&mut self, force: Vector3<f32>) {
+        self.force += force;
+    }
+
+    /// Turns the body during the next step, the moment of inertia is taken as the mass.
+    pub fn apply_torque(&mut self, torque: f32) {
+        self.torque += torque;
+    }
+
+    /// Changes the velocity at once, like a hit or a jump.
+    pub fn apply_impulse(&mut self, impulse: Vector3<f32>) {
+        self.velocity += impulse * self.inverse_mass();
+    }
+}
+
+/// Moves the rendered objects that have a body one step, with semi-implicit Euler.
+pub fn integrate(objects: &mut [Object3d], gravity: &Vector3<f32>, dt: f32) {
+    for obj in objects.iter_mut().filter(|obj| obj.render) {
+        let Some(body) = obj.body.as_mut() else {
+            continue;
+        };
+        let inverse_mass = body.inverse_mass();
+        if inverse_mass > 0.0 {
+            body.velocity += (body.force * inverse_mass + gravity * body.gravity_scale) * dt;
+            body.angular_velocity += body.torque * inverse_mass * dt;
+        }
+        body.velocity /= 1.0 + body.linear_damping * dt;
+        body.angular_velocity /= 1.0 + body.angular_damping * dt;
+        body.force = Vector3::zeros();
+        body.torque = 0.0;
+
+        obj.position += body.velocity * dt;
+        obj.rotation += body.angular_velocity * dt;
+    }
+}
+
+/// Pushes two touching objects apart and changes their velocities with the restitution and
+/// friction of their bodies. The contact normal goes from `a` to `b`.
+pub fn resolve_contact(a: &mut Object3d, b: &mut Object3d, contact: &Contact) {
+    let inverse_mass_a = a.body.as_ref().map_or(0.0, RigidBody::inverse_mass);
+    let inverse_mass_b = b.body.as_ref().map_or(0.0, RigidBody::inverse_mass);
+    let inverse_mass_sum = inverse_mass_a + inverse_mass_b;
+    if inverse_mass_sum <= 0.0 {
+        return;
+    }
+    let normal = contact.normal;
+
+    let correction = normal
+        * ((contact.depth - PENETRATION_SLOP).max(0.0) * POSITION_CORRECTION / inverse_mass_sum);
+    a.position -= correction * inverse_mass_a;
+    b.position += correction * inverse_mass_b;
+
+    let velocity_a = a
+        .body
+        .as_ref()
+        .map_or(Vector3::zeros(), |body| body.velocity);
+    let velocity_b = b
+        .body
+        .as_ref()
+        .map_or(Vector3::zeros(), |body| body.velocity);
+    let relative_velocity = velocity_b - velocity_a;
+    let normal_speed = relative_velocity.dot(&normal);
+    // Already moving apart
+    if normal_speed > 0.0 {
+        return;
+    }
+
+    // The bounciest body and the mean friction of both
+    let restitution = a
+        .body
+        .as_ref()
+        .map_or(0.0, |body| body.restitution)
+        .max(b.body.as_ref().map_or(0.0, |body| body.restitution));
+    let friction = (a.body.as_ref().map_or(1.0, |body| body.friction)
+        * b.body.as_ref().map_or(1.0, |body| body.friction))
+    .sqrt();
+
+    let normal_impulse = -(1.0 + restitution) * normal_speed / inverse_mass_sum;
+    let mut impulse = normal * normal_impulse;
+
+    let tangent_velocity = relative_velocity - normal * normal_speed;
+    if let Some(tangent) = tangent_velocity.try_normalize(f32::EPSILON) {
+        let tangent_impulse = (-relative_velocity.dot(&tangent) / inverse_mass_sum)
+            .clamp(-normal_impulse * friction, normal_impulse * friction);
+        impulse += tangent * tangent_impulse;
+    }
+
+    if let Some(body) = a.body.as_mut() {
+        body.velocity -= impulse * inverse_mass_a;
+    }
+    if let Some(body) = b.body.as_mut() {
+        body.velocity += impulse * inverse_mass_b;
+    }
+}
+
+/// Resolves every contact found by the collision world, see `resolve_contact`.
+pub fn resolve_contacts(objects: &mut [Object3d], contacts: &[(usize, usize, Contact)]) {
+    for &(i, j, contact) in contacts {
+        if i == j {
+            continue;
+        }
+        // Two mutable borrows of the slice, one from each side of the split
+        let (a, b) = if i < j {
+            let (left, right) = objects.split_at_mut(j);
+            (&mut left[i], &mut right[0])
+        } else {
+            let (left, right) = objects.split_at_mut(i);
+            (&mut right[0], &mut left[j])
+        };
+        resolve_contact(a, b, &contact);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::cube_at;
+
+    fn with_body(id: u32, body: RigidBody) -> Object3d {
+        let mut obj = cube_at(id, Vector3::zeros());
+        obj.body = Some(body);
+        obj
+    }
+
+    fn moving(velocity: Vector3<f32>, restitution: f32, friction: f32) -> RigidBody {
+        RigidBody {
+            velocity,
+            restitution,
+            friction,
+            ..RigidBody::default()
+        }
+    }
+
+    fn assert_near(a: Vector3<f32>, b: Vector3<f32>) {
+        assert!((a - b).norm() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn gravity_changes_the_velocity_before_the_position() {
+        let mut objects = vec![with_body(1, RigidBody::default())];
+        let gravity = Vector3::new(0.0, -10.0, 0.0);
+        integrate(&mut objects, &gravity, 0.1);
+        assert_near(objects[0].body.as_ref().unwrap().velocity, gravity * 0.1);
+        assert_near(objects[0].position, Vector3::new(0.0, -0.1, 0.0));
+        integrate(&mut objects, &gravity, 0.1);
+        assert_near(objects[0].position, Vector3::new(0.0, -0.3, 0.0));
+    }
+
+    #[test]
+    fn damping_slows_the_body_down() {
+        let body = RigidBody {
+            velocity: Vector3::new(10.0, 0.0, 0.0),
+            linear_damping: 1.0,
+            angular_velocity: 4.0,
+            angular_damping: 3.0,
+            ..RigidBody::default()
+        };
+        let mut objects = vec![with_body(1, body)];
+        integrate(&mut objects, &Vector3::zeros(), 0.5);
+        let body = objects[0].body.as_ref().unwrap();
+        assert_near(body.velocity, Vector3::new(10.0 / 1.5, 0.0, 0.0));
+        assert!((body.angular_velocity - 4.0 / 2.5).abs() < 1e-5);
+        assert_near(objects[0].position, Vector3::new(10.0 / 3.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn kinematic_bodies_ignore_forces_but_keep_moving() {
+        let body = RigidBody {
+            mass: 0.0,
+            velocity: Vector3::new(1.0, 0.0, 0.0),
+            ..RigidBody::default()
+        };
+        let mut objects = vec![with_body(1, body)];
+        let body = objects[0].body.as_mut().unwrap();
+        body.apply_force(Vector3::new(0.0, 100.0, 0.0));
+        body.apply_impulse(Vector3::new(0.0, 100.0, 0.0));
+        integrate(&mut objects, &Vector3::new(0.0, -10.0, 0.0), 1.0);
+        assert_near(objects[0].body.as_ref().unwrap().velocity, Vector3::x());
+        assert_near(objects[0].position, Vector3::x());
+    }
+
+    #[test]
+    fn impulses_and_forces_are_divided_by_the_mass() {
+        let mut body = RigidBody {
+            mass: 4.0,
+            ..RigidBody::default()
+        };
+        body.apply_impulse(Vector3::new(8.0, 0.0, 0.0));
+        assert_near(body.velocity, Vector3::new(2.0, 0.0, 0.0));
+
+        body.apply_force(Vector3::new(-8.0, 0.0, 0.0));
+        let mut objects = vec![with_body(1, body)];
+        integrate(&mut objects, &Vector3::zeros(), 0.5);
+        assert_near(objects[0].body.as_ref().unwrap().velocity, Vector3::x());
+        // Forces last one step
+        integrate(&mut objects, &Vector3::zeros(), 0.5);
+        assert_near(objects[0].body.as_ref().unwrap().velocity, Vector3::x());
+    }
+
+    #[test]
+    fn bodies_bounce_off_static_objects_with_their_restitution() {
+        let mut floor = cube_at(1, Vector3::zeros());
+        let mut ball = with_body(2, moving(Vector3::new(0.0, -2.0, 0.0), 0.5, 0.5));
+        let contact = Contact {
+            normal: Vector3::y(),
+            depth: 0.11,
+        };
+        resolve_contact(&mut floor, &mut ball, &contact);
+        assert_near(
+            ball.body.as_ref().unwrap().velocity,
+            Vector3::new(0.0, 1.0, 0.0),
+        );
+        // Only the body moves out of the overlap, by the part over the slop
+        assert_near(floor.position, Vector3::zeros());
+        assert_near(ball.position, Vector3::new(0.0, 0.08, 0.0));
+    }
+
+    #[test]
+    fn friction_limits_the_sliding_impulse() {
+        let contact = Contact {
+            normal: Vector3::y(),
+            depth: 0.0,
+        };
+        for (friction, speed) in [(0.0, 3.0), (0.25, 2.0), (1.0, 1.0)] {
+            let mut floor = cube_at(1, Vector3::zeros());
+            let mut ball = with_body(2, moving(Vector3::new(3.0, -2.0, 0.0), 0.0, friction));
+            resolve_contact(&mut floor, &mut ball, &contact);
+            assert_near(
+                ball.body.as_ref().unwrap().velocity,
+                Vector3::new(speed, 0.0, 0.0),
+            );
+        }
+    }
+
+    #[test]
+    fn heavier_bodies_move_less() {
+        let mut light = with_body(1, moving(Vector3::new(2.0, 0.0, 0.0), 0.0, 0.0));
+        let mut heavy = with_body(
+            2,
+            RigidBody {
+                mass: 3.0,
+                ..moving(Vector3::zeros(), 0.0, 0.0)
+            },
+        );
+        let contact = Contact {
+            normal: Vector3::x(),
+            depth: 0.41,
+        };
+        resolve_contact(&mut light, &mut heavy, &contact);
+        // Without bounce both end with the speed of their center of mass
+        assert_near(
+            light.body.as_ref().unwrap().velocity,
+            Vector3::new(0.5, 0.0, 0.0),
+        );
+        assert_near(
+            heavy.body.as_ref().unwrap().velocity,
+            Vector3::new(0.5, 0.0, 0.0),
+        );
+        assert_near(light.position, Vector3::new(-0.24, 0.0, 0.0));
+        assert_near(heavy.position, Vector3::new(0.08, 0.0, 0.0));
+    }
+
+    #[test]
+    fn static_objects_do_not_resolve_against_each_other() {
+        let mut a = cube_at(1, Vector3::zeros());
+        let mut b = with_body(
+            2,
+            RigidBody {
+                mass: 0.0,
+                ..RigidBody::default()
+            },
+        );
+        let contact = Contact {
+            normal: Vector3::x(),
+            depth: 1.0,
+        };
+        resolve_contact(&mut a, &mut b, &contact);
+        assert_near(a.position, Vector3::zeros());
+        assert_near(b.position, Vector3::zeros());
+    }
+}
@@ -0,0 +1,619 @@
+use na::{Matrix3, Vector3};
+use std::collections::HashSet;
+
+use crate::collision::{gjk, WorldShape};
+use crate::object3d::Object3d;
+use crate::spatial::SpatialGrid;
+
+// The GJK ray cast stops when the ray point is this close to the shape
+const RAY_TOLERANCE: f32 = 1e-4;
+const RAY_MAX_ITERATIONS: usize = 64;
+
+/// The first object a ray hits.
+#[derive(Clone, Copy, Debug)]
+pub struct RayHit {
+    pub id: u32,
+    pub point: Vector3<f32>,
+    // Unit vector out of the surface that was hit
+    pub normal: Vector3<f32>,
+    pub distance: f32,
+}
+
+/// Which objects a query sees.
+#[derive(Clone, Debug)]
+pub struct QueryFilter {
+    // Layers seen, like the mask of a `CollisionFilter`
+    pub mask: u32,
+    // Triggers are skipped unless this is set
+    pub triggers: bool,
+    // Ids skipped, like the object making the query
+    pub ignore: Vec<u32>,
+}
+
+impl Default for QueryFilter {
+    fn default() -> Self {
+        QueryFilter {
+            mask: u32::MAX,
+            triggers: false,
+            ignore: Vec::new(),
+        }
+    }
+}
+
+impl QueryFilter {
+    pub fn accepts(&self, obj: &Object3d) -> bool {
+        obj.render
+            && obj.filter.layers & self.mask != 0
+            && (self.triggers || !obj.trigger)
+            && !self.ignore.contains(&obj.random_id)
+    }
+}
+
+/// What a ray is tested against.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RayTarget {
+    // The colliders, objects without one are ignored
+    Colliders,
+    // The triangles of the meshes of every object, slower but exact
+    Meshes,
+}
+
+/// Casts a ray against the objects the filter accepts and returns the closest hit within
+/// `max_distance`. `dir` does not need to be normalized. `grid` must be synced with `objects`.
+pub fn raycast(
+    objects: &[Object3d],
+    grid: &SpatialGrid,
+    origin: &Vector3<f32>,
+    dir: &Vector3<f32>,
+    max_distance: f32,
+    target: RayTarget,
+    filter: &QueryFilter,
+) -> Option<RayHit> {
+    let dir = dir.try_normalize(f32::EPSILON)?;
+    let mut closest: Option<RayHit> = None;
+    // Objects in several cells are tested once
+    let mut tested = HashSet::new();
+    grid.traverse_ray(origin, &dir, max_distance, |indices, exit| {
+        for &index in indices {
+            if !tested.insert(index) {
+                continue;
+            }
+            let Some(obj) = objects.get(index).filter(|obj| filter.accepts(obj)) else {
+                continue;
+            };
+            let max_distance = closest.map_or(max_distance, |hit| hit.distance);
+            let hit = match target {
+                RayTarget::Colliders => obj.collision_shape().and_then(|shape| {
+                    shape.bounds().ray_interval(origin, &dir, max_distance)?;
+                    ray_shape(&shape, origin, &dir, max_distance)
+                }),
+                RayTarget::Meshes => ray_mesh(obj, origin, &dir, max_distance),
+            };
+            if let Some((distance, normal)) = hit {
+                closest = Some(RayHit {
+                    id: obj.random_id,
+                    point: origin + dir * distance,
+                    normal,
+                    distance,
+                });
+            }
+        }
+        // Nothing in the cells ahead can be closer
+        closest.is_some_and(|hit| hit.distance <= exit)
+    });
+    closest
+}
+
+/// Ids of the objects the filter accepts whose collider overlaps the sphere.
+pub fn overlap_sphere(
+    objects: &[Object3d],
+    grid: &SpatialGrid,
+    center: &Vector3<f32>,
+    radius: f32,
+    filter: &QueryFilter,
+) -> Vec<u32> {
+    overlap_shape(
+        objects,
+        grid,
+        &WorldShape::Sphere {
+            center: *center,
+            radius,
+        },
+        filter,
+    )
+}
+
+/// Ids of the objects the filter accepts whose collider overlaps the box, turned `rotation`
+/// radians around the y axis like the objects.
+pub fn overlap_box(
+    objects: &[Object3d],
+    grid: &SpatialGrid,
+    center: &Vector3<f32>,
+    half_extents: &Vector3<f32>,
+    rotation: f32,
+    filter: &QueryFilter,
+) -> Vec<u32> {
+    overlap_shape(
+        objects,
+        grid,
+        &WorldShape::Box {
+            center: *center,
+            axes: Object3d::yaw_matrix(rotation),
+            half_extents: *half_extents,
+        },
+        filter,
+    )
+}
+
+fn overlap_shape(
+    objects: &[Object3d],
+    grid: &SpatialGrid,
+    query: &WorldShape,
+    filter: &QueryFilter,
+) -> Vec<u32> {
+    let query_bounds = query.bounds();
+    grid.query_bounds(&query_bounds)
+        .into_iter()
+        .filter_map(|index| objects.get(index))
+        .filter(|obj| filter.accepts(obj))
+        .filter(|obj| {
+            obj.collision_shape().is_some_and(|shape| {
+                shape.bounds().overlaps(&query_bounds) && gjk(&shape, query).is_some()
+            })
+        })
+        .map(|obj| obj.random_id)
+        .collect()
+}
+
+/// Distance and normal of the first point of a convex shape on the ray, with the GJK ray cast
+/// of van den Bergen. A ray starting inside hits at distance 0, facing back along the ray.
+pub fn ray_shape(
+    shape: &WorldShape,
+    origin: &Vector3<f32>,
+    dir: &Vector3<f32>,
+    max_distance: f32,
+) -> Option<(f32, Vector3<f32>)> {
+    ray_support(|v| shape.support(v), origin, dir, max_distance)
+}
+
+/// How far `moving` can travel along `dir` before touching `target`, and the normal of the
+/// contact, pointing from `target` to `moving`. It is a ray cast against the Minkowski
+/// difference of the shapes. Shapes already overlapping hit at distance 0.
+pub fn shape_cast(
+    moving: &WorldShape,
+    dir: &Vector3<f32>,
+    max_distance: f32,
+    target: &WorldShape,
+) -> Option<(f32, Vector3<f32>)> {
+    ray_support(
+        |v| target.support(v) - moving.support(&-v),
+        &Vector3::zeros(),
+        dir,
+        max_distance,
+    )
+}
+
+/// The GJK ray cast over any convex shape, given by its support function. A cast that does not
+/// converge is reported as a miss.
+fn ray_support(
+    support: impl Fn(&Vector3<f32>) -> Vector3<f32>,
+    origin: &Vector3<f32>,
+    dir: &Vector3<f32>,
+    max_distance: f32,
+) -> Option<(f32, Vector3<f32>)> {
+    let mut distance = 0.0;
+    let mut point = *origin;
+    let mut normal = Vector3::zeros();
+    // Points of the shape whose hull, seen from the ray point, is closest to it
+    let mut support_set: Vec<Vector3<f32>> = Vec::new();
+    let mut v = point - support(&Vector3::x());
+    let hit = |distance: f32, normal: Vector3<f32>| {
+        Some((distance, normal.try_normalize(f32::EPSILON).unwrap_or(-dir)))
+    };
+
+    for _ in 0..RAY_MAX_ITERATIONS {
+        if v.norm_squared() <= RAY_TOLERANCE * RAY_TOLERANCE {
+            return hit(distance, normal);
+        }
+        let vertex = support(&v);
+        let w = point - vertex;
+        if v.dot(&w) > 0.0 {
+            if v.dot(dir) >= 0.0 {
+                return None;
+            }
+            distance -= v.dot(&w) / v.dot(dir);
+            if distance > max_distance {
+                return None;
+            }
+            point = origin + dir * distance;
+            normal = v;
+        }
+        if support_set.len() == 4 {
+            // The ray point is inside the tetrahedron
+            return hit(distance, normal);
+        }
+        support_set.push(vertex);
+        let relative: Vec<Vector3<f32>> = support_set.iter().map(|p| point - p).collect();
+        let (closest, used) = closest_to_origin(&relative);
+        support_set = used.iter().map(|&i| support_set[i]).collect();
+        v = closest;
+    }
+    // Not converged, the ray point may still be away from the shape
+    None
+}
+
+/// Closest point to the origin of the convex hull of up to four points, with the indices of the
+/// points it lies between. Every subset is tried, which is cheap for so few points.
+fn closest_to_origin(points: &[Vector3<f32>]) -> (Vector3<f32>, Vec<usize>) {
+    let mut best = (points[0], vec![0]);
+    for mask in 1..(1u32 << points.len()) {
+        let subset: Vec<usize> = (0..points.len()).filter(|i| mask & (1 << i) != 0).collect();
+        let Some(closest) = closest_on_affine_hull(points, &subset) else {
+            continue;
+        };
+        if closest.norm_squared() < best.0.norm_squared() {
+            best = (closest, subset);
+        }
+    }
+    best
+}
+
+/// Closest point to the origin of the plane, line or point through the points of the subset,
+/// `None` when it falls outside of them or they are degenerate.
+fn closest_on_affine_hull(points: &[Vector3<f32>], subset: &[usize]) -> Option<Vector3<f32>> {
+    // In f64: with large shapes the rounding of f32 swamps the small distances near contact
+    let base: Vector3<f64> = points[subset[0]].cast();
+    let edges: Vec<Vector3<f64>> = subset[1..]
+        .iter()
+        .map(|&i| points[i].cast() - base)
+        .collect();
+    if edges.is_empty() {
+        return Some(base.cast());
+    }
+    // Least squares for the weights of the edges: (EᵀE) w = -Eᵀ base
+    let n = edges.len();
+    let mut gram = Matrix3::<f64>::identity();
+    let mut rhs = Vector3::<f64>::zeros();
+    for i in 0..n {
+        for j in 0..n {
+            gram[(i, j)] = edges[i].dot(&edges[j]);
+        }
+        rhs[i] = -edges[i].dot(&base);
+    }
+    let weights = gram.try_inverse()? * rhs;
+    let weights = &weights.as_slice()[..n];
+    let base_weight = 1.0 - weights.iter().sum::<f64>();
+    let tolerance = f32::EPSILON as f64;
+    if base_weight < -tolerance || weights.iter().any(|&w| w < -tolerance) {
+        return None;
+    }
+    let closest = edges
+        .iter()
+        .zip(weights)
+        .fold(base, |point, (edge, &w)| point + edge * w);
+    Some(closest.cast())
+}
+
+/// Distance and normal of the first triangle of the mesh on the ray, with Möller-Trumbore.
+fn ray_mesh(
+    obj: &Object3d,
+    origin: &Vector3<f32>,
+    dir: &Vector3<f32>,
+    max_distance: f32,
+) -> Option<(f32, Vector3<f32>)> {
+    let points = obj.world_points();
+    let mut closest: Option<(f32, Vector3<f32>)> = None;
+    for face in &obj.faces {
+        // Faces with more than three corners are split in a fan
+        for i in 1..face.len().saturating_sub(1) {
+            let (a, b, c) = (points[face[0]], points[face[i]], points[face[i + 1]]);
+            let limit = closest.map_or(max_distance, |(distance, _)| distance);
+            if let Some(distance) = ray_triangle(origin, dir, &a, &b, &c, limit) {
+                let normal = (b - a).cross(&(c - a)).normalize();
+                // Face the ray whatever the winding of the triangle
+                let normal = if normal.dot(dir) > 0.0 {
+                    -normal
+                } else {
+                    normal
+                };
+                closest = Some((distance, normal));
+            }
+        }
+    }
+    closest
+}
+
+fn ray_triangle(
+    origin: &Vector3<f32>,
+    dir: &Vector3<f32>,
+    a: &Vector3<f32>,
+    b: &Vector3<f32>,
+    c: &Vector3<f32>,
+    max_distance: f32,
+) -> Option<f32> {
+    let ab = b - a;
+    let ac = c - a;
+    let p = dir.cross(&ac);
+    let det = ab.dot(&p);
+    if det.abs() < f32::EPSILON {
+        return None;
+    }
+    let inverse_det = 1.0 / det;
+    let t = origin - a;
+    let u = t.dot(&p) * inverse_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = t.cross(&ab);
+    let v = dir.dot(&q) * inverse_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let distance = ac.dot(&q) * inverse_det;
+    (distance >= 0.0 && distance <= max_distance).then_some(distance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collision::ColliderKind;
+    use crate::test_util::cube_at;
+
+    fn assert_near(actual: &Vector3<f32>, expected: &Vector3<f32>) {
+        assert!(
+            (actual - expected).norm() < 1e-3,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    fn unit_cube() -> WorldShape {
+        WorldShape::Box {
+            center: Vector3::zeros(),
+            axes: Matrix3::identity(),
+            half_extents: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    fn scene(colliders: bool) -> (Vec<Object3d>, SpatialGrid) {
+        let objects: Vec<Object3d> = [0.0, 6.0]
+            .iter()
+            .zip(1..)
+            .map(|(&x, id)| {
+                let mut obj = cube_at(id, Vector3::new(x, 0.0, 0.0));
+                if colliders {
+                    obj.set_collider(Some(ColliderKind::Aabb));
+                }
+                obj
+            })
+            .collect();
+        let mut grid = SpatialGrid::new(2.0);
+        grid.sync(&objects);
+        (objects, grid)
+    }
+
+    #[test]
+    fn filters_skip_hidden_ignored_triggers_and_masked_objects() {
+        let mut obj = cube_at(1, Vector3::zeros());
+        obj.filter.layers = 1 << 2;
+        let all = QueryFilter::default();
+        assert!(all.accepts(&obj));
+        let masked = QueryFilter {
+            mask: 1 | 1 << 1,
+            ..QueryFilter::default()
+        };
+        assert!(!masked.accepts(&obj));
+        let ignoring = QueryFilter {
+            ignore: vec![obj.random_id],
+            ..QueryFilter::default()
+        };
+        assert!(!ignoring.accepts(&obj));
+
+        obj.trigger = true;
+        assert!(!all.accepts(&obj));
+        let with_triggers = QueryFilter {
+            triggers: true,
+            ..QueryFilter::default()
+        };
+        assert!(with_triggers.accepts(&obj));
+
+        obj.render = false;
+        assert!(!with_triggers.accepts(&obj));
+    }
+
+    #[test]
+    fn rays_hit_spheres_at_the_right_distance_and_normal() {
+        let sphere = WorldShape::Sphere {
+            center: Vector3::zeros(),
+            radius: 1.0,
+        };
+        let origin = Vector3::new(-5.0, 0.0, 0.0);
+        let (distance, normal) = ray_shape(&sphere, &origin, &Vector3::x(), 100.0).unwrap();
+        assert!((distance - 4.0).abs() < 1e-3, "distance {}", distance);
+        assert_near(&normal, &-Vector3::x());
+
+        let origin = Vector3::new(-5.0, 0.6, 0.0);
+        let (distance, normal) = ray_shape(&sphere, &origin, &Vector3::x(), 100.0).unwrap();
+        assert!((distance - 4.2).abs() < 1e-3, "distance {}", distance);
+        // On a curved surface the normal comes from the last step of the cast, so only close
+        let expected = Vector3::new(-0.8, 0.6, 0.0);
+        assert!(normal.dot(&expected) > 0.9999, "normal {}", normal);
+    }
+
+    #[test]
+    fn rays_hit_boxes_or_miss_them() {
+        let cube = unit_cube();
+        let origin = Vector3::new(0.5, -5.0, 0.0);
+        let (distance, normal) = ray_shape(&cube, &origin, &Vector3::y(), 100.0).unwrap();
+        assert!((distance - 4.0).abs() < 1e-3, "distance {}", distance);
+        assert_near(&normal, &-Vector3::y());
+
+        assert!(ray_shape(&cube, &origin, &Vector3::y(), 3.9).is_none());
+        assert!(ray_shape(&cube, &origin, &-Vector3::y(), 100.0).is_none());
+        let beside = Vector3::new(1.5, -5.0, 0.0);
+        assert!(ray_shape(&cube, &beside, &Vector3::y(), 100.0).is_none());
+
+        let (distance, _) = ray_shape(&cube, &Vector3::zeros(), &Vector3::x(), 100.0).unwrap();
+        assert_eq!(distance, 0.0);
+    }
+
+    #[test]
+    fn shapes_cast_until_they_touch() {
+        let sphere = WorldShape::Sphere {
+            center: Vector3::new(-5.0, 0.0, 0.0),
+            radius: 1.0,
+        };
+        let (distance, normal) = shape_cast(&sphere, &Vector3::x(), 10.0, &unit_cube()).unwrap();
+        assert!((distance - 3.0).abs() < 1e-3, "distance {}", distance);
+        assert_near(&normal, &-Vector3::x());
+
+        assert!(shape_cast(&sphere, &Vector3::x(), 2.9, &unit_cube()).is_none());
+        assert!(shape_cast(&sphere, &Vector3::y(), 10.0, &unit_cube()).is_none());
+    }
+
+    #[test]
+    fn casts_converge_near_large_shapes() {
+        // Far corners make the support points big next to the small gap
+        let floor = WorldShape::Box {
+            center: Vector3::new(0.0, 0.5, 0.0),
+            axes: Matrix3::identity(),
+            half_extents: Vector3::new(30.0, 0.5, 30.0),
+        };
+        let small = WorldShape::Box {
+            center: Vector3::new(0.0, -0.51, 0.0),
+            axes: Matrix3::identity(),
+            half_extents: Vector3::new(0.5, 0.5, 0.5),
+        };
+        let (distance, normal) = shape_cast(&small, &Vector3::y(), 0.02, &floor).unwrap();
+        assert!((distance - 0.01).abs() < 1e-3, "distance {}", distance);
+        assert_near(&normal, &-Vector3::y());
+    }
+
+    #[test]
+    fn raycast_returns_the_closest_object() {
+        let (objects, grid) = scene(true);
+        let origin = Vector3::new(-10.0, 0.2, 0.1);
+        let filter = QueryFilter::default();
+
+        let hit = raycast(
+            &objects,
+            &grid,
+            &origin,
+            &Vector3::new(3.0, 0.0, 0.0),
+            100.0,
+            RayTarget::Colliders,
+            &filter,
+        )
+        .unwrap();
+        assert_eq!(hit.id, objects[0].random_id);
+        assert!(
+            (hit.distance - 9.0).abs() < 1e-3,
+            "distance {}",
+            hit.distance
+        );
+        assert_near(&hit.point, &Vector3::new(-1.0, 0.2, 0.1));
+        assert_near(&hit.normal, &-Vector3::x());
+
+        let behind = QueryFilter {
+            ignore: vec![objects[0].random_id],
+            ..QueryFilter::default()
+        };
+        let hit = raycast(
+            &objects,
+            &grid,
+            &origin,
+            &Vector3::x(),
+            100.0,
+            RayTarget::Colliders,
+            &behind,
+        )
+        .unwrap();
+        assert_eq!(hit.id, objects[1].random_id);
+        assert!(
+            (hit.distance - 15.0).abs() < 1e-3,
+            "distance {}",
+            hit.distance
+        );
+
+        let short = raycast(
+            &objects,
+            &grid,
+            &origin,
+            &Vector3::x(),
+            8.0,
+            RayTarget::Colliders,
+            &filter,
+        );
+        assert!(short.is_none());
+    }
+
+    #[test]
+    fn raycast_against_meshes_needs_no_collider() {
+        let (objects, grid) = scene(false);
+        let origin = Vector3::new(0.3, 0.2, -10.0);
+        let filter = QueryFilter::default();
+        let collider_hit = raycast(
+            &objects,
+            &grid,
+            &origin,
+            &Vector3::z(),
+            100.0,
+            RayTarget::Colliders,
+            &filter,
+        );
+        assert!(collider_hit.is_none());
+
+        let hit = raycast(
+            &objects,
+            &grid,
+            &origin,
+            &Vector3::z(),
+            100.0,
+            RayTarget::Meshes,
+            &filter,
+        )
+        .unwrap();
+        assert_eq!(hit.id, objects[0].random_id);
+        assert!(
+            (hit.distance - 9.0).abs() < 1e-3,
+            "distance {}",
+            hit.distance
+        );
+        assert_near(&hit.normal, &-Vector3::z());
+    }
+
+    #[test]
+    fn overlaps_find_the_colliders_inside() {
+        let (objects, grid) = scene(true);
+        let filter = QueryFilter::default();
+        let center = Vector3::new(2.5, 0.0, 0.0);
+        assert!(overlap_sphere(&objects, &grid, &center, 1.0, &filter).is_empty());
+        assert_eq!(
+            overlap_sphere(&objects, &grid, &center, 2.0, &filter),
+            vec![objects[0].random_id]
+        );
+
+        let mut found = overlap_sphere(&objects, &grid, &Vector3::new(3.0, 0.0, 0.0), 2.5, &filter);
+        found.sort_unstable();
+        let mut both = vec![objects[0].random_id, objects[1].random_id];
+        both.sort_unstable();
+        assert_eq!(found, both);
+
+        // A thin box reaches the cube only when turned towards it
+        let half_extents = Vector3::new(0.1, 0.5, 1.6);
+        let center = Vector3::new(2.2, 0.0, 0.0);
+        assert!(overlap_box(&objects, &grid, &center, &half_extents, 0.0, &filter).is_empty());
+        assert_eq!(
+            overlap_box(
+                &objects,
+                &grid,
+                &center,
+                &half_extents,
+                std::f32::consts::FRAC_PI_2,
+                &filter
+            ),
+            vec![objects[0].random_id]
+        );
+    }
+}
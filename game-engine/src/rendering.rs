@@ -5,13 +5,18 @@ use winit::dpi::PhysicalSize;
 use winit::window::Window;
 
 use crate::camera3d::Camera3d;
+use crate::config::RendererConfig;
 use crate::object3d::Object3d;
+use crate::spatial::SpatialGrid;
 
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
     pub position: [f32; 2],
-    pub color: [f32; 3],
+    // RGBA, the alpha blends the line with what is behind it
+    pub color: [f32; 4],
+    // Distance from the middle of the line across it and half its width, in pixels
+    pub across: [f32; 2],
 }
 
 impl Vertex {
@@ -29,70 +34,305 @@ impl Vertex {
                 wgpu::VertexAttribute {
                     offset: mem::size_of::<[f32; 2]>() as wgpu::BufferAddress,
                     shader_location: 1,
-                    format: wgpu::VertexFormat::Float32x3,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 6]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x2,
                 },
             ],
         }
     }
 }
 
+// Lines are quads a pixel wider than the line on each side; the fragments of that
+// border fade out with their distance to the line, which smooths its edges
 const SHADER: &str = r#"
 struct VsOutput {
     @builtin(position) position: vec4<f32>,
-    @location(1) color: vec3<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) across: vec2<f32>,
 };
 
 @vertex
-fn vs_main(@location(0) in_position: vec2<f32>, @location(1) in_color: vec3<f32>) -> VsOutput {
-    return VsOutput(vec4<f32>(in_position, 0.0, 1.0), in_color);
+fn vs_main(
+    @location(0) in_position: vec2<f32>,
+    @location(1) in_color: vec4<f32>,
+    @location(2) in_across: vec2<f32>,
+) -> VsOutput {
+    return VsOutput(vec4<f32>(in_position, 0.0, 1.0), in_color, in_across);
 }
 
 @fragment
-fn fs_main(@location(1) in_color: vec3<f32>) -> @location(0) vec4<f32> {
-    return vec4<f32>(in_color, 1.0);
+fn fs_main(@location(1) in_color: vec4<f32>, @location(2) in_across: vec2<f32>) -> @location(0) vec4<f32> {
+    let coverage = clamp(in_across.y + 0.5 - abs(in_across.x), 0.0, 1.0);
+    return vec4<f32>(in_color.rgb, in_color.a * coverage);
 }
 "#;
 
+/// Where the frames are drawn.
+pub enum RenderTarget {
+    Window {
+        window: Arc<Window>,
+        surface: wgpu::Surface<'static>,
+        config: wgpu::SurfaceConfiguration,
+    },
+    // Headless, the frame stays on the texture until it is read back with `read_frame`
+    Offscreen {
+        texture: wgpu::Texture,
+    },
+}
+
 pub struct Renderer {
-    pub window: Arc<Window>,
+    pub target: RenderTarget,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
     pub size: PhysicalSize<u32>,
-    pub surface: wgpu::Surface<'static>,
     pub surface_format: wgpu::TextureFormat,
     pub render_pipeline: wgpu::RenderPipeline,
     pub camera: Camera3d,
+    pub clear_color: wgpu::Color,
+    // Distances from the camera where lines start fading and where they are gone
+    pub depth_fade: Option<[f32; 2]>,
+    // Width of the lines in logical pixels, scaled by the scale factor of the window
+    pub line_width: f32,
+    // Samples per pixel, 1 without multisampling
+    sample_count: u32,
+    // Drawn to instead of the frame when multisampling, then resolved to it
+    msaa_texture: Option<wgpu::Texture>,
+    // When set, the next draw copies its frame to `captured` before presenting it
+    capture_requested: bool,
+    captured: Option<Vec<u8>>,
+    stats: RenderStats,
 }
 
+/// What the last draw did.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RenderStats {
+    // Objects with `render` set
+    pub objects: usize,
+    pub drawn: usize,
+    // Objects skipped because they are off screen or behind the camera
+    pub culled: usize,
+    pub lines: usize,
+}
+
+// Format of the offscreen texture, read back as RGBA bytes
+const OFFSCREEN_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+// Pixels the quad of a line extends past its width, where its edge fades out
+const LINE_FEATHER: f32 = 1.0;
+
 impl Renderer {
-    pub async fn new(window: Arc<Window>, _width: u32, _height: u32, camera: Camera3d) -> Self {
+    /// Creates a renderer drawing to the window. `width` and `height` are the configured size of
+    /// the window, used when it has not reported its own yet.
+    pub async fn new(
+        window: Arc<Window>,
+        width: u32,
+        height: u32,
+        mut camera: Camera3d,
+        settings: &RendererConfig,
+    ) -> Self {
         let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions::default())
             .await
             .expect("Didn't find an appropriate adapter");
         let (device, queue) = adapter
-            .request_device(&wgpu::DeviceDescriptor::default(), None)
+            .request_device(&Self::device_descriptor(&adapter), None)
             .await
             .expect("Failed to create device");
 
-        let size = window.inner_size();
+        // Some platforms report no size until the window is shown
+        let mut size = window.inner_size();
+        if size.width == 0 || size.height == 0 {
+            size = PhysicalSize::new(width, height);
+        }
+        camera.set_aspect(size.width as f32 / size.height as f32);
         let surface = instance.create_surface(window.clone()).unwrap();
         let caps = surface.get_capabilities(&adapter);
         let surface_format = caps.formats[0];
 
+        // Copying the frame is needed for screenshots, not every platform allows it
         let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | (caps.usages & wgpu::TextureUsages::COPY_SRC),
             format: surface_format,
             view_formats: vec![surface_format.add_srgb_suffix()],
             alpha_mode: wgpu::CompositeAlphaMode::Auto,
             width: size.width,
             height: size.height,
             desired_maximum_frame_latency: 2,
-            present_mode: wgpu::PresentMode::AutoVsync,
+            present_mode: settings.vsync.present_mode(),
         };
         surface.configure(&device, &config);
 
+        let sample_count =
+            Self::supported_samples(&adapter, &device, surface_format, settings.msaa);
+        let render_pipeline = Self::create_pipeline(&device, surface_format, sample_count);
+        let msaa_texture = Self::create_msaa_texture(&device, size, surface_format, sample_count);
+
+        Self {
+            target: RenderTarget::Window {
+                window,
+                surface,
+                config,
+            },
+            device,
+            queue,
+            size,
+            surface_format,
+            render_pipeline,
+            camera,
+            clear_color: Self::clear_color(settings),
+            depth_fade: settings.depth_fade,
+            line_width: settings.line_width,
+            sample_count,
+            msaa_texture,
+            capture_requested: false,
+            captured: None,
+            stats: RenderStats::default(),
+        }
+    }
+
+    /// Creates a renderer without window that draws to an offscreen texture.
+    /// If there is no GPU it falls back to a software adapter.
+    pub async fn new_headless(
+        width: u32,
+        height: u32,
+        mut camera: Camera3d,
+        settings: &RendererConfig,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+        let adapter = match instance
+            .request_adapter(&wgpu::RequestAdapterOptions::default())
+            .await
+        {
+            Some(adapter) => adapter,
+            None => instance
+                .request_adapter(&wgpu::RequestAdapterOptions {
+                    force_fallback_adapter: true,
+                    ..Default::default()
+                })
+                .await
+                .ok_or("Didn't find an appropriate adapter")?,
+        };
+        let (device, queue) = adapter
+            .request_device(&Self::device_descriptor(&adapter), None)
+            .await?;
+
+        let size = PhysicalSize::new(width.max(1), height.max(1));
+        camera.set_aspect(size.width as f32 / size.height as f32);
+        let texture = Self::create_offscreen_texture(&device, size);
+        let sample_count =
+            Self::supported_samples(&adapter, &device, OFFSCREEN_FORMAT, settings.msaa);
+        let render_pipeline = Self::create_pipeline(&device, OFFSCREEN_FORMAT, sample_count);
+        let msaa_texture = Self::create_msaa_texture(&device, size, OFFSCREEN_FORMAT, sample_count);
+
+        Ok(Self {
+            target: RenderTarget::Offscreen { texture },
+            device,
+            queue,
+            size,
+            surface_format: OFFSCREEN_FORMAT,
+            render_pipeline,
+            camera,
+            clear_color: Self::clear_color(settings),
+            depth_fade: settings.depth_fade,
+            line_width: settings.line_width,
+            sample_count,
+            msaa_texture,
+            capture_requested: false,
+            captured: None,
+            stats: RenderStats::default(),
+        })
+    }
+
+    fn clear_color(settings: &RendererConfig) -> wgpu::Color {
+        let [r, g, b] = settings.clear_color;
+        wgpu::Color { r, g, b, a: 1.0 }
+    }
+
+    fn create_offscreen_texture(device: &wgpu::Device, size: PhysicalSize<u32>) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Offscreen Texture"),
+            size: wgpu::Extent3d {
+                width: size.width,
+                height: size.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: OFFSCREEN_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        })
+    }
+
+    /// Asks for the format features of the adapter when it has them, without them only 1 and
+    /// 4 samples are allowed.
+    fn device_descriptor(adapter: &wgpu::Adapter) -> wgpu::DeviceDescriptor<'static> {
+        wgpu::DeviceDescriptor {
+            required_features: adapter.features()
+                & wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
+            ..Default::default()
+        }
+    }
+
+    /// The sample count asked for when the device can draw to `format` with it, 1 otherwise.
+    fn supported_samples(
+        adapter: &wgpu::Adapter,
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        requested: u32,
+    ) -> u32 {
+        let features = device.features();
+        let flags = if features.contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES) {
+            adapter.get_texture_format_features(format).flags
+        } else {
+            format.guaranteed_format_features(features).flags
+        };
+        if requested <= 1 || flags.sample_count_supported(requested) {
+            return requested.max(1);
+        }
+        eprintln!(
+            "El adaptador no soporta msaa = {}, se dibuja sin multisampling",
+            requested
+        );
+        1
+    }
+
+    fn create_msaa_texture(
+        device: &wgpu::Device,
+        size: PhysicalSize<u32>,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Option<wgpu::Texture> {
+        if sample_count <= 1 {
+            return None;
+        }
+        Some(device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Multisample Texture"),
+            size: wgpu::Extent3d {
+                width: size.width.max(1),
+                height: size.height.max(1),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        }))
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> wgpu::RenderPipeline {
         let shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: Some("Shader WGSL"),
             source: wgpu::ShaderSource::Wgsl(SHADER.into()),
@@ -102,7 +342,7 @@ impl Renderer {
             bind_group_layouts: &[],
             push_constant_ranges: &[],
         });
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: Some("Line Pipeline"),
             layout: Some(&pipeline_layout),
             vertex: wgpu::VertexState {
@@ -112,7 +352,7 @@ impl Renderer {
                 compilation_options: Default::default(),
             },
             primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::LineList,
+                topology: wgpu::PrimitiveTopology::TriangleList,
                 ..Default::default()
             },
             fragment: Some(wgpu::FragmentState {
@@ -120,27 +360,19 @@ impl Renderer {
                 entry_point: Some("fs_main"),
                 compilation_options: Default::default(),
                 targets: &[Some(wgpu::ColorTargetState {
-                    format: surface_format,
+                    format,
                     blend: Some(wgpu::BlendState::ALPHA_BLENDING),
                     write_mask: wgpu::ColorWrites::ALL,
                 })],
             }),
             depth_stencil: None,
-            multisample: wgpu::MultisampleState::default(),
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
             multiview: None,
             cache: None,
-        });
-
-        Self {
-            window,
-            device,
-            queue,
-            size,
-            surface,
-            surface_format,
-            render_pipeline,
-            camera,
-        }
+        })
     }
 
     fn pixel_to_ndc(&self, x: f32, y: f32) -> [f32; 2] {
@@ -150,42 +382,95 @@ impl Renderer {
     }
 
     pub fn resize(&mut self, new_size: PhysicalSize<u32>) {
+        // Minimized windows report a zero size, there is nothing to draw on
+        if new_size.width == 0 || new_size.height == 0 {
+            return;
+        }
         self.size = new_size;
-        let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: self.surface_format,
-            view_formats: vec![self.surface_format.add_srgb_suffix()],
-            alpha_mode: wgpu::CompositeAlphaMode::Auto,
-            width: new_size.width,
-            height: new_size.height,
-            desired_maximum_frame_latency: 2,
-            present_mode: wgpu::PresentMode::AutoVsync,
-        };
-        self.surface.configure(&self.device, &config);
+        self.camera
+            .set_aspect(new_size.width as f32 / new_size.height as f32);
+        match &mut self.target {
+            RenderTarget::Window {
+                surface, config, ..
+            } => {
+                config.width = new_size.width;
+                config.height = new_size.height;
+                surface.configure(&self.device, config);
+            }
+            RenderTarget::Offscreen { texture } => {
+                *texture = Self::create_offscreen_texture(&self.device, new_size);
+            }
+        }
+        self.msaa_texture = Self::create_msaa_texture(
+            &self.device,
+            new_size,
+            self.surface_format,
+            self.sample_count,
+        );
     }
 
-    pub fn get_window(&self) -> &Window {
-        &self.window
+    /// The window of the renderer, `None` when it is headless.
+    pub fn get_window(&self) -> Option<&Window> {
+        match &self.target {
+            RenderTarget::Window { window, .. } => Some(window),
+            RenderTarget::Offscreen { .. } => None,
+        }
     }
 
     /// This method loop over the objects, transforms their points, projects them with the camera,
     /// and generates the line segments.
     /// After that, it dynamically generates a vertex buffer and issues the draw command with wgpu.
-    pub fn draw(&mut self, objects: &mut [Object3d]) -> Result<(), wgpu::SurfaceError> {
+    /// Only the objects `grid` finds in the box of the camera frustum are looked at, so `grid`
+    /// must be synced with `objects`.
+    /// `alpha` is how far the frame is between the last two simulation steps, from 0 to 1.
+    pub fn draw(
+        &mut self,
+        objects: &mut [Object3d],
+        grid: &SpatialGrid,
+        alpha: f32,
+    ) -> Result<(), wgpu::SurfaceError> {
         let proj_matrix = self.camera.projection_matrix();
         let screen_center_x = self.size.width as f32 / 2.0;
         let screen_center_y = self.size.height as f32 / 2.0;
 
+        let frustum = self
+            .camera
+            .frustum(self.size.width as f32, self.size.height as f32);
+        let mut stats = RenderStats::default();
+        let scale = self
+            .get_window()
+            .map_or(1.0, |window| window.scale_factor() as f32);
+        let half_width = self.line_width * scale / 2.0;
+
         let mut vertices: Vec<Vertex> = Vec::new();
-        for object in objects.iter_mut() {
-            if !object.render {
+        // The grid only holds the rendered objects
+        stats.objects = grid.len();
+        for index in grid.query_bounds(frustum.bounds()) {
+            let Some(object) = objects.get_mut(index) else {
+                continue;
+            };
+            let (center, radius) = object.bounding_sphere_at(alpha);
+            if !frustum.contains_sphere(&center, radius) {
                 continue;
             }
-            let transformed = object.transform_points();
+            stats.drawn += 1;
+            let transformed = object.transform_points_at(alpha);
+            // Opacity of each point, by its depth in front of the camera
+            let fades: Vec<f32> = transformed
+                .iter()
+                .map(|p| match self.depth_fade {
+                    Some([start, end]) => {
+                        let depth = p.z - self.camera.position.z;
+                        1.0 - ((depth - start) / (end - start).max(f32::EPSILON)).clamp(0.0, 1.0)
+                    }
+                    None => 1.0,
+                })
+                .collect();
             let pts2d: Vec<(f32, f32)> = transformed
                 .iter()
                 .map(|p| {
-                    if let Some((x, y)) = self.camera.project_point_with(p, &proj_matrix) {
+                    let p = p - self.camera.position;
+                    if let Some((x, y)) = self.camera.project_point_with(&p, &proj_matrix) {
                         (screen_center_x + x * 100.0, screen_center_y - y * 100.0)
                     } else {
                         (0.0, 0.0)
@@ -193,25 +478,22 @@ impl Renderer {
                 })
                 .collect();
             for edge in &object.edges {
-                for window_edge in edge.windows(2) {
-                    let a_idx = window_edge[0];
-                    let b_idx = window_edge[1];
-                    let (ax, ay) = pts2d[a_idx];
-                    let (bx, by) = pts2d[b_idx];
-                    let pos_a = self.pixel_to_ndc(ax, ay);
-                    let pos_b = self.pixel_to_ndc(bx, by);
-                    vertices.push(Vertex {
-                        position: pos_a,
-                        color: [1.0, 1.0, 1.0],
-                    });
-                    vertices.push(Vertex {
-                        position: pos_b,
-                        color: [1.0, 1.0, 1.0],
-                    });
-                }
+                let [r, g, b, a] = object.face_color(edge.face);
+                let faded = |point: usize| [r, g, b, a * fades[point]];
+                self.push_line(
+                    &mut vertices,
+                    pts2d[edge.a],
+                    pts2d[edge.b],
+                    [faded(edge.a), faded(edge.b)],
+                    half_width,
+                );
+                stats.lines += 1;
             }
         }
 
+        stats.culled = stats.objects - stats.drawn;
+        self.stats = stats;
+
         let vertex_buffer = self
             .device
             .create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -220,10 +502,19 @@ impl Renderer {
                 usage: wgpu::BufferUsages::VERTEX,
             });
 
-        let frame = self.surface.get_current_texture()?;
-        let view = frame
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
+        let (frame, view) = match &self.target {
+            RenderTarget::Window { surface, .. } => {
+                let frame = surface.get_current_texture()?;
+                let view = frame
+                    .texture
+                    .create_view(&wgpu::TextureViewDescriptor::default());
+                (Some(frame), view)
+            }
+            RenderTarget::Offscreen { texture } => (
+                None,
+                texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            ),
+        };
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -231,18 +522,22 @@ impl Renderer {
             });
 
         {
+            // With multisampling the samples are drawn apart and averaged into the frame
+            let msaa_view = self
+                .msaa_texture
+                .as_ref()
+                .map(|texture| texture.create_view(&wgpu::TextureViewDescriptor::default()));
+            let (target_view, resolve_target) = match &msaa_view {
+                Some(msaa_view) => (msaa_view, Some(&view)),
+                None => (&view, None),
+            };
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
+                    view: target_view,
+                    resolve_target,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.0,
-                            g: 0.0,
-                            b: 0.0,
-                            a: 1.0,
-                        }),
+                        load: wgpu::LoadOp::Clear(self.clear_color),
                         store: wgpu::StoreOp::Store,
                     },
                 })],
@@ -251,13 +546,222 @@ impl Renderer {
                 occlusion_query_set: None,
             });
 
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
-            render_pass.draw(0..(vertices.len() as u32), 0..1);
+            // Empty buffers can not be bound
+            if !vertices.is_empty() {
+                render_pass.set_pipeline(&self.render_pipeline);
+                render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+                render_pass.draw(0..(vertices.len() as u32), 0..1);
+            }
         }
 
         self.queue.submit(std::iter::once(encoder.finish()));
-        frame.present();
+        if self.capture_requested {
+            self.capture_requested = false;
+            let texture = match (&frame, &self.target) {
+                (Some(frame), _) => &frame.texture,
+                (None, RenderTarget::Offscreen { texture }) => texture,
+                (None, RenderTarget::Window { .. }) => unreachable!("Windows always have a frame"),
+            };
+            match self.read_texture(texture) {
+                Ok(pixels) => self.captured = Some(pixels),
+                Err(e) => eprintln!("Error capturando el frame: {}", e),
+            }
+        }
+        if let Some(frame) = frame {
+            frame.present();
+        }
         Ok(())
     }
+
+    /// Adds the two triangles of a line between two points in pixels, as wide as twice
+    /// `half_width` plus the fading border. The ends stick out half the width, so the lines of
+    /// a corner meet.
+    fn push_line(
+        &self,
+        vertices: &mut Vec<Vertex>,
+        (ax, ay): (f32, f32),
+        (bx, by): (f32, f32),
+        [color_a, color_b]: [[f32; 4]; 2],
+        half_width: f32,
+    ) {
+        let length = (bx - ax).hypot(by - ay);
+        // A line with no length is still drawn as a dot
+        let (dx, dy) = if length > f32::EPSILON {
+            ((bx - ax) / length, (by - ay) / length)
+        } else {
+            (1.0, 0.0)
+        };
+        let reach = half_width + LINE_FEATHER;
+        let (nx, ny) = (-dy * reach, dx * reach);
+        let (ex, ey) = (dx * half_width, dy * half_width);
+        let corner = |x: f32, y: f32, side: f32, color: [f32; 4]| Vertex {
+            position: self.pixel_to_ndc(x + nx * side, y + ny * side),
+            color,
+            across: [reach * side, half_width],
+        };
+        let a_left = corner(ax - ex, ay - ey, 1.0, color_a);
+        let a_right = corner(ax - ex, ay - ey, -1.0, color_a);
+        let b_left = corner(bx + ex, by + ey, 1.0, color_b);
+        let b_right = corner(bx + ex, by + ey, -1.0, color_b);
+        vertices.extend_from_slice(&[a_left, a_right, b_left, b_left, a_right, b_right]);
+    }
+
+    /// Counts of the last `draw`.
+    pub fn stats(&self) -> RenderStats {
+        self.stats
+    }
+
+    /// Makes the next `draw` keep a copy of its frame, get it with `take_capture`.
+    pub fn request_capture(&mut self) {
+        self.capture_requested = true;
+    }
+
+    /// The frame copied by the last `draw` after `request_capture`, as in `read_frame`.
+    pub fn take_capture(&mut self) -> Option<Vec<u8>> {
+        self.captured.take()
+    }
+
+    /// Copies the last drawn frame of a headless renderer to memory,
+    /// as RGBA bytes row by row from the top left corner.
+    pub fn read_frame(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let RenderTarget::Offscreen { texture } = &self.target else {
+            return Err("Only headless renderers can read their frame back".into());
+        };
+        self.read_texture(texture)
+    }
+
+    fn read_texture(&self, texture: &wgpu::Texture) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if !texture.usage().contains(wgpu::TextureUsages::COPY_SRC) {
+            return Err("The frame of this surface can not be copied".into());
+        }
+        // The PNG encoder takes 8 bit RGBA, window surfaces are usually BGRA
+        let swap_red_blue = match texture.format() {
+            wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb => false,
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb => true,
+            other => return Err(format!("Frames in {:?} can not be saved as PNG", other).into()),
+        };
+        let pixel_size = texture
+            .format()
+            .block_copy_size(None)
+            .ok_or("The frame format can not be copied")?;
+        let width = texture.width();
+        let height = texture.height();
+        let unpadded_row = width * pixel_size;
+        // Buffer copies need rows aligned to 256 bytes
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_row = unpadded_row.div_ceil(align) * align;
+
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Readback Buffer"),
+            size: (padded_row * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Readback Encoder"),
+            });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::TexelCopyBufferInfo {
+                buffer: &buffer,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            texture.size(),
+        );
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver.recv()??;
+
+        let mut pixels = Vec::with_capacity((unpadded_row * height) as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks(padded_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_row as usize]);
+            }
+        }
+        buffer.unmap();
+
+        if swap_red_blue {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+        Ok(pixels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::cube_at;
+    use na::Vector3;
+    use std::fs::File;
+    use std::path::Path;
+
+    // Reference frame of `headless_frames_match_the_golden_image`, rewritten when
+    // PEQUE_UPDATE_GOLDEN is set
+    const GOLDEN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/golden/cube.png");
+    const SIZE: u32 = 64;
+
+    fn read_png(path: &str) -> (u32, u32, Vec<u8>) {
+        let decoder = png::Decoder::new(std::io::BufReader::new(File::open(path).unwrap()));
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(info.color_type, png::ColorType::Rgba);
+        pixels.truncate(info.buffer_size());
+        (info.width, info.height, pixels)
+    }
+
+    #[test]
+    fn headless_frames_match_the_golden_image() {
+        let camera = Camera3d::new(60.0, 1.0, 0.1, 1000.0);
+        let settings = RendererConfig::default();
+        let mut renderer =
+            match pollster::block_on(Renderer::new_headless(SIZE, SIZE, camera, &settings)) {
+                Ok(renderer) => renderer,
+                Err(e) => {
+                    eprintln!("Skipping the golden image test, no adapter: {}", e);
+                    return;
+                }
+            };
+        let mut objects = vec![cube_at(1, Vector3::new(0.0, 0.0, 9.0))];
+        objects[0].rotation = 0.6;
+        let mut grid = SpatialGrid::new(10.0);
+        grid.sync(&objects);
+        renderer.draw(&mut objects, &grid, 1.0).unwrap();
+        let pixels = renderer.read_frame().unwrap();
+
+        if std::env::var_os("PEQUE_UPDATE_GOLDEN").is_some() {
+            crate::capture::save_png(Path::new(GOLDEN), SIZE, SIZE, &pixels).unwrap();
+        }
+        let (width, height, golden) = read_png(GOLDEN);
+        assert_eq!((width, height), (SIZE, SIZE));
+        // Adapters rasterize the edges of the lines a bit differently
+        let different = pixels
+            .chunks(4)
+            .zip(golden.chunks(4))
+            .filter(|(a, b)| a.iter().zip(b.iter()).any(|(a, b)| a.abs_diff(*b) > 48))
+            .count();
+        let allowed = (SIZE * SIZE / 50) as usize;
+        assert!(
+            different <= allowed,
+            "{} pixels differ from {}, at most {} may",
+            different,
+            GOLDEN,
+            allowed
+        );
+    }
 }
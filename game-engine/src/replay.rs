@@ -0,0 +1,324 @@
+use device_query::Keycode;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use crate::input::Input;
+
+// Name of every key in the files. Both ways go through this table because the names of
+// device_query do not parse back for every key
+const KEY_NAMES: [(Keycode, &str); 96] = [
+    (Keycode::Key0, "Key0"),
+    (Keycode::Key1, "Key1"),
+    (Keycode::Key2, "Key2"),
+    (Keycode::Key3, "Key3"),
+    (Keycode::Key4, "Key4"),
+    (Keycode::Key5, "Key5"),
+    (Keycode::Key6, "Key6"),
+    (Keycode::Key7, "Key7"),
+    (Keycode::Key8, "Key8"),
+    (Keycode::Key9, "Key9"),
+    (Keycode::A, "A"),
+    (Keycode::B, "B"),
+    (Keycode::C, "C"),
+    (Keycode::D, "D"),
+    (Keycode::E, "E"),
+    (Keycode::F, "F"),
+    (Keycode::G, "G"),
+    (Keycode::H, "H"),
+    (Keycode::I, "I"),
+    (Keycode::J, "J"),
+    (Keycode::K, "K"),
+    (Keycode::L, "L"),
+    (Keycode::M, "M"),
+    (Keycode::N, "N"),
+    (Keycode::O, "O"),
+    (Keycode::P, "P"),
+    (Keycode::Q, "Q"),
+    (Keycode::R, "R"),
+    (Keycode::S, "S"),
+    (Keycode::T, "T"),
+    (Keycode::U, "U"),
+    (Keycode::V, "V"),
+    (Keycode::W, "W"),
+    (Keycode::X, "X"),
+    (Keycode::Y, "Y"),
+    (Keycode::Z, "Z"),
+    (Keycode::F1, "F1"),
+    (Keycode::F2, "F2"),
+    (Keycode::F3, "F3"),
+    (Keycode::F4, "F4"),
+    (Keycode::F5, "F5"),
+    (Keycode::F6, "F6"),
+    (Keycode::F7, "F7"),
+    (Keycode::F8, "F8"),
+    (Keycode::F9, "F9"),
+    (Keycode::F10, "F10"),
+    (Keycode::F11, "F11"),
+    (Keycode::F12, "F12"),
+    (Keycode::Escape, "Escape"),
+    (Keycode::Space, "Space"),
+    (Keycode::LControl, "LControl"),
+    (Keycode::RControl, "RControl"),
+    (Keycode::LShift, "LShift"),
+    (Keycode::RShift, "RShift"),
+    (Keycode::LAlt, "LAlt"),
+    (Keycode::RAlt, "RAlt"),
+    (Keycode::Meta, "Meta"),
+    (Keycode::Enter, "Enter"),
+    (Keycode::Up, "Up"),
+    (Keycode::Down, "Down"),
+    (Keycode::Left, "Left"),
+    (Keycode::Right, "Right"),
+    (Keycode::Backspace, "Backspace"),
+    (Keycode::CapsLock, "CapsLock"),
+    (Keycode::Tab, "Tab"),
+    (Keycode::Home, "Home"),
+    (Keycode::End, "End"),
+    (Keycode::PageUp, "PageUp"),
+    (Keycode::PageDown, "PageDown"),
+    (Keycode::Insert, "Insert"),
+    (Keycode::Delete, "Delete"),
+    (Keycode::Numpad0, "Numpad0"),
+    (Keycode::Numpad1, "Numpad1"),
+    (Keycode::Numpad2, "Numpad2"),
+    (Keycode::Numpad3, "Numpad3"),
+    (Keycode::Numpad4, "Numpad4"),
+    (Keycode::Numpad5, "Numpad5"),
+    (Keycode::Numpad6, "Numpad6"),
+    (Keycode::Numpad7, "Numpad7"),
+    (Keycode::Numpad8, "Numpad8"),
+    (Keycode::Numpad9, "Numpad9"),
+    (Keycode::NumpadSubtract, "NumpadSubtract"),
+    (Keycode::NumpadAdd, "NumpadAdd"),
+    (Keycode::NumpadDivide, "NumpadDivide"),
+    (Keycode::NumpadMultiply, "NumpadMultiply"),
+    (Keycode::Grave, "Grave"),
+    (Keycode::Minus, "Minus"),
+    (Keycode::Equal, "Equal"),
+    (Keycode::LeftBracket, "LeftBracket"),
+    (Keycode::RightBracket, "RightBracket"),
+    (Keycode::BackSlash, "BackSlash"),
+    (Keycode::Semicolon, "Semicolon"),
+    (Keycode::Apostrophe, "Apostrophe"),
+    (Keycode::Comma, "Comma"),
+    (Keycode::Dot, "Dot"),
+    (Keycode::Slash, "Slash"),
+];
+
+fn key_name(key: &Keycode) -> &'static str {
+    KEY_NAMES
+        .iter()
+        .find(|(known, _)| known == key)
+        .map(|(_, name)| *name)
+        .expect("Every keycode has a name")
+}
+
+fn key_from_name(name: &str) -> Option<Keycode> {
+    KEY_NAMES
+        .iter()
+        .find(|(_, known)| *known == name)
+        .map(|(key, _)| key.clone())
+}
+
+/// Writes every frame's `dt` and `Input` to a text file so the session can be replayed.
+///
+/// The first line is `seed <n>`, the seed of `math.random` in Lua.
+/// Every other line is a frame: `dt<TAB>pressing<TAB>just_pressed<TAB>just_released<TAB>text`,
+/// with the keys of each list separated by commas and the text escaped.
+pub struct InputRecorder {
+    writer: BufWriter<File>,
+}
+
+impl InputRecorder {
+    pub fn create(path: &str, seed: u64) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "seed {}", seed)?;
+        Ok(InputRecorder { writer })
+    }
+
+    pub fn record(&mut self, dt: f32, inputs: &Input) -> io::Result<()> {
+        writeln!(
+            self.writer,
+            "{}\t{}\t{}\t{}\t{}",
+            dt,
+            Self::join_keys(&inputs.input),
+            Self::join_keys(&inputs.just_pressed),
+            Self::join_keys(&inputs.just_released),
+            Self::escape_text(&inputs.text),
+        )?;
+        self.writer.flush()
+    }
+
+    fn join_keys(keys: &[Keycode]) -> String {
+        keys.iter().map(key_name).collect::<Vec<&str>>().join(",")
+    }
+
+    fn escape_text(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\t' => escaped.push_str("\\t"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                other => escaped.push(other),
+            }
+        }
+        escaped
+    }
+}
+
+/// Feeds back the frames written by an `InputRecorder`, in order.
+pub struct InputReplay {
+    pub seed: u64,
+    frames: VecDeque<(f32, Input)>,
+}
+
+impl InputReplay {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut lines = reader.lines();
+
+        let header = lines.next().unwrap_or_else(|| Ok(String::new()))?;
+        let seed = header
+            .strip_prefix("seed ")
+            .and_then(|s| s.trim().parse().ok())
+            .ok_or_else(|| Self::invalid(&format!("missing seed header: {:?}", header)))?;
+
+        let mut frames = VecDeque::new();
+        for line in lines {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            frames.push_back(Self::parse_frame(&line)?);
+        }
+
+        Ok(InputReplay { seed, frames })
+    }
+
+    /// Returns the next recorded frame, or `None` when the recording is over.
+    pub fn next_frame(&mut self) -> Option<(f32, Input)> {
+        self.frames.pop_front()
+    }
+
+    fn parse_frame(line: &str) -> io::Result<(f32, Input)> {
+        let parts: Vec<&str> = line.split('\t').collect();
+        if parts.len() != 5 {
+            return Err(Self::invalid(&format!("malformed frame: {:?}", line)));
+        }
+        let dt: f32 = parts[0]
+            .parse()
+            .map_err(|_| Self::invalid(&format!("invalid dt: {:?}", parts[0])))?;
+        Ok((
+            dt,
+            Input {
+                input: Self::parse_keys(parts[1])?,
+                just_pressed: Self::parse_keys(parts[2])?,
+                just_released: Self::parse_keys(parts[3])?,
+                text: Self::unescape_text(parts[4]),
+            },
+        ))
+    }
+
+    fn parse_keys(list: &str) -> io::Result<Vec<Keycode>> {
+        list.split(',')
+            .filter(|key| !key.is_empty())
+            .map(|key| {
+                key_from_name(key).ok_or_else(|| Self::invalid(&format!("unknown key: {:?}", key)))
+            })
+            .collect()
+    }
+
+    fn unescape_text(escaped: &str) -> String {
+        let mut text = String::with_capacity(escaped.len());
+        let mut chars = escaped.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                text.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('t') => text.push('\t'),
+                Some('n') => text.push('\n'),
+                Some('r') => text.push('\r'),
+                Some(other) => text.push(other),
+                None => {}
+            }
+        }
+        text
+    }
+
+    fn invalid(message: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("peque_{}_{}.txt", name, std::process::id()))
+    }
+
+    #[test]
+    fn key_names_are_unique() {
+        for (i, (key, name)) in KEY_NAMES.iter().enumerate() {
+            for (other_key, other_name) in &KEY_NAMES[i + 1..] {
+                assert_ne!(key, other_key);
+                assert_ne!(name, other_name);
+            }
+        }
+    }
+
+    #[test]
+    fn every_key_replays_as_recorded() {
+        let path = temp_path("keys");
+        let keys: Vec<Keycode> = KEY_NAMES.iter().map(|(key, _)| key.clone()).collect();
+        let frames = [
+            Input {
+                input: keys.clone(),
+                just_pressed: keys.clone(),
+                just_released: Vec::new(),
+                text: "a\tb\\c\nñ".to_string(),
+            },
+            Input::default(),
+            Input {
+                input: Vec::new(),
+                just_pressed: Vec::new(),
+                just_released: keys.clone(),
+                text: String::new(),
+            },
+        ];
+        let mut recorder = InputRecorder::create(path.to_str().unwrap(), 42).unwrap();
+        for (i, frame) in frames.iter().enumerate() {
+            recorder.record(0.5 + i as f32, frame).unwrap();
+        }
+        drop(recorder);
+
+        let mut replay = InputReplay::open(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replay.seed, 42);
+        for (i, expected) in frames.iter().enumerate() {
+            let (dt, frame) = replay.next_frame().unwrap();
+            assert_eq!(dt, 0.5 + i as f32);
+            assert_eq!(frame.input, expected.input);
+            assert_eq!(frame.just_pressed, expected.just_pressed);
+            assert_eq!(frame.just_released, expected.just_released);
+            assert_eq!(frame.text, expected.text);
+        }
+        assert!(replay.next_frame().is_none());
+    }
+
+    #[test]
+    fn unknown_keys_are_an_error() {
+        let path = temp_path("unknown");
+        std::fs::write(&path, "seed 1\n0.016\tA,NotAKey\t\t\t\n").unwrap();
+        let result = InputReplay::open(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -1,28 +1,98 @@
-use mlua::{Lua, MultiValue, Result as LuaResult, Table, Value};
+use mlua::{Lua, MultiValue, Result as LuaResult, Table, UserData, UserDataMethods, Value};
 use na::Vector3;
-use std::{cell::RefCell, rc::Rc};
+use std::path::Path;
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
 
+use super::character::CharacterController;
+use super::collision::{ColliderKind, CollisionWorld};
+use super::config::ScriptsConfig;
 use super::input::Input;
-use super::object3d::Object3d;
-use super::sound_system::SoundSystem;
+use super::mixer::MUSIC_BUS;
+use super::object3d::{Object3d, Rgba};
+use super::physics::{self, RigidBody};
+use super::query::{self, QueryFilter, RayTarget};
+use super::rendering::RenderStats;
+use super::sound_system::{PlayOptions, SoundError, SoundHandle, SoundSystem};
+use super::spatial::SpatialGrid;
+use super::spatial_audio::{Emitter, Falloff, Listener};
+use super::synth::{Filter, ToneSpec, Waveform};
+
+// Cell size of the spatial index until `set_cell_size` is called
+const DEFAULT_CELL_SIZE: f32 = 10.0;
+
+/// A change to a rigid body asked by a script, applied after every object has been updated.
+enum BodyCommand {
+    Force(u32, Vector3<f32>),
+    Torque(u32, f32),
+    Impulse(u32, Vector3<f32>),
+    Velocity(u32, Vector3<f32>),
+    AngularVelocity(u32, f32),
+}
 
 pub struct LuaInt {
     lua: Lua,
     pub objects: Rc<RefCell<Vec<Object3d>>>,
     pub pending_objects: Rc<RefCell<Vec<Object3d>>>,
-    // pub sound_system: Rc<SoundSystem>,
+    // Paths passed to `screenshot` this frame, saved by the main loop after drawing
+    pub screenshots: Rc<RefCell<Vec<String>>>,
+    // Counts of the last frame drawn, set by the main loop
+    pub render_stats: Rc<Cell<RenderStats>>,
+    sound_system: Rc<SoundSystem>,
+    collisions: RefCell<CollisionWorld>,
+    // Where the objects are, kept in sync as they move; the queries and collisions use it
+    spatial: Rc<RefCell<SpatialGrid>>,
+    body_commands: Rc<RefCell<Vec<BodyCommand>>>,
+    gravity: Rc<Cell<Vector3<f32>>>,
 }
 
 impl LuaInt {
+    /// Creates the Lua context and runs the start script.
+    /// `seed` drives `math.random` and object ids count up from 1 in creation order, so a replay
+    /// can reproduce a session.
+    /// The scripts and every path the scripts use are relative to `root`, the project folder.
     #[allow(dependency_on_unit_never_type_fallback)]
-    pub fn new() -> LuaResult<Self> {
+    pub fn new(
+        seed: u64,
+        sound_system: SoundSystem,
+        root: &Path,
+        scripts: &ScriptsConfig,
+    ) -> LuaResult<Self> {
         // RC to share the objects between the Lua context and the main thread
-        let sound_system = Rc::new(SoundSystem::new().expect("Failed to initialize SoundSystem"));
+        let sound_system = Rc::new(sound_system);
 
         let objects = Rc::new(RefCell::new(vec![]));
         let pending_objects = Rc::new(RefCell::new(Vec::new()));
+        let screenshots = Rc::new(RefCell::new(Vec::new()));
+        let render_stats = Rc::new(Cell::new(RenderStats::default()));
+        let body_commands = Rc::new(RefCell::new(Vec::new()));
+        let spatial = Rc::new(RefCell::new(SpatialGrid::new(DEFAULT_CELL_SIZE)));
+        let gravity = Rc::new(Cell::new(Vector3::zeros()));
+        let next_id = Rc::new(Cell::new(1u32));
+        let root = Rc::new(root.to_path_buf());
         let lua = Lua::new();
 
+        {
+            let math: Table = lua.globals().get("math")?;
+            let randomseed: mlua::Function = math.get("randomseed")?;
+            randomseed.call::<()>(seed as i64)?;
+        }
+
+        {
+            // So `require` finds the modules of the project
+            let package: Table = lua.globals().get("package")?;
+            let path: String = package.get("path")?;
+            let project_path = format!(
+                "{};{};{}",
+                Self::resolve(&root, "?.lua"),
+                Self::resolve(&root, "?/init.lua"),
+                path
+            );
+            package.set("path", project_path)?;
+        }
+
         {
             lua.globals().set(
                 "print",
@@ -42,16 +112,57 @@ impl LuaInt {
 
         {
             let pending_objects_clone = Rc::clone(&pending_objects);
+            let next_id = Rc::clone(&next_id);
+            let root = Rc::clone(&root);
             lua.globals().set(
                 "new_model",
                 lua.create_function_mut(
-                    move |_, (model_path, pos_table, rotation): (String, Table, f32)| {
-                        let x: f32 = pos_table.get("x")?;
-                        let y: f32 = pos_table.get("y")?;
-                        let z: f32 = pos_table.get("z")?;
-                        let new_obj = Object3d::new(&model_path, Vector3::new(x, y, z), rotation);
+                    move |_,
+                          (model_path, pos_table, rotation, options): (
+                        String,
+                        Table,
+                        f32,
+                        Option<Table>,
+                    )| {
+                        let position = Self::vector_from_table(&pos_table)?;
+                        let id = next_id.get();
+                        next_id.set(id + 1);
+                        let mut new_obj = Object3d::new(
+                            id,
+                            &Self::resolve(&root, &model_path),
+                            position,
+                            rotation,
+                        );
+                        // Scripts tell objects apart by the path they gave, not the resolved one
+                        new_obj.object_name = model_path;
+                        if let Some(options) = options {
+                            if let Some(name) = options.get::<Option<String>>("collider")? {
+                                let kind = ColliderKind::from_name(&name).ok_or_else(|| {
+                                    mlua::Error::RuntimeError(format!("Unknown collider: {}", name))
+                                })?;
+                                new_obj.set_collider(Some(kind));
+                            }
+                            new_obj.trigger =
+                                options.get::<Option<bool>>("trigger")?.unwrap_or(false);
+                            if new_obj.trigger && new_obj.collider.is_none() {
+                                return Err(mlua::Error::RuntimeError(
+                                    "A trigger needs a collider".to_string(),
+                                ));
+                            }
+                            if let Some(layers) = Self::layer_bits(options.get("layer")?)? {
+                                new_obj.filter.layers = layers;
+                            }
+                            if let Some(mask) = Self::layer_bits(options.get("mask")?)? {
+                                new_obj.filter.mask = mask;
+                            }
+                            new_obj.body = Self::rigid_body(options.get("body")?)?;
+                            if let Some(color) = options.get::<Option<Table>>("color")? {
+                                new_obj.color = Self::color_from_table(&color)?;
+                            }
+                        }
+                        let id = new_obj.random_id;
                         pending_objects_clone.borrow_mut().push(new_obj);
-                        Ok(())
+                        Ok(id)
                     },
                 )?,
             )?;
@@ -59,58 +170,794 @@ impl LuaInt {
 
         {
             let sound_system = Rc::clone(&sound_system);
+            let root = Rc::clone(&root);
             lua.globals().set(
                 "play_sound",
-                lua.create_function_mut(move |_, (path,): (String,)| {
+                lua.create_function_mut(move |_, (path, options): (String, Option<Table>)| {
+                    let options = Self::play_options(options)?;
+                    sound_system
+                        .play_sound(&Self::resolve(&root, &path), &options)
+                        .map_err(Self::sound_error)
+                })?,
+            )?;
+        }
+
+        {
+            let sound_system = Rc::clone(&sound_system);
+            lua.globals().set(
+                "play_tone",
+                lua.create_function_mut(move |_, (table,): (Table,)| {
+                    let spec = Self::tone_spec(&table)?;
+                    let options = Self::play_options(Some(table))?;
+                    sound_system
+                        .play_tone(spec, &options)
+                        .map_err(Self::sound_error)
+                })?,
+            )?;
+        }
+
+        {
+            let sound_system = Rc::clone(&sound_system);
+            let root = Rc::clone(&root);
+            lua.globals().set(
+                "play_music",
+                lua.create_function_mut(move |_, (path, options): (String, Option<Table>)| {
+                    let (looping, fade) = match options {
+                        Some(options) => (
+                            options.get::<Option<bool>>("loop")?.unwrap_or(true),
+                            options.get::<Option<f32>>("fade")?.unwrap_or(0.0),
+                        ),
+                        None => (true, 0.0),
+                    };
+                    sound_system
+                        .play_music(&Self::resolve(&root, &path), looping, fade)
+                        .map_err(Self::sound_error)
+                })?,
+            )?;
+        }
+
+        {
+            let sound_system = Rc::clone(&sound_system);
+            lua.globals().set(
+                "stop_music",
+                lua.create_function_mut(move |_, (fade,): (Option<f32>,)| {
+                    sound_system
+                        .stop_music(fade.unwrap_or(0.0))
+                        .map_err(Self::sound_error)
+                })?,
+            )?;
+        }
+
+        {
+            let sound_system = Rc::clone(&sound_system);
+            lua.globals().set(
+                "set_music_volume",
+                lua.create_function_mut(move |_, (volume,): (f32,)| {
+                    sound_system
+                        .set_bus_volume(MUSIC_BUS, volume)
+                        .map_err(Self::sound_error)
+                })?,
+            )?;
+        }
+
+        {
+            let sound_system = Rc::clone(&sound_system);
+            lua.globals().set(
+                "set_bus_volume",
+                lua.create_function_mut(move |_, (bus, volume): (String, f32)| {
+                    sound_system
+                        .set_bus_volume(&bus, volume)
+                        .map_err(Self::sound_error)
+                })?,
+            )?;
+        }
+
+        {
+            let sound_system = Rc::clone(&sound_system);
+            lua.globals().set(
+                "get_bus_volume",
+                lua.create_function_mut(move |_, (bus,): (String,)| {
+                    sound_system.bus_volume(&bus).map_err(Self::sound_error)
+                })?,
+            )?;
+        }
+
+        {
+            let sound_system = Rc::clone(&sound_system);
+            lua.globals().set(
+                "set_bus_muted",
+                lua.create_function_mut(move |_, (bus, muted): (String, bool)| {
+                    sound_system
+                        .set_bus_muted(&bus, muted)
+                        .map_err(Self::sound_error)
+                })?,
+            )?;
+        }
+
+        {
+            let sound_system = Rc::clone(&sound_system);
+            lua.globals().set(
+                "set_max_voices",
+                lua.create_function_mut(move |_, (max_voices,): (usize,)| {
                     sound_system
-                        .play_sound(&path)
-                        .expect("Failed to play sound");
+                        .set_max_voices(max_voices)
+                        .map_err(Self::sound_error)
+                })?,
+            )?;
+        }
+
+        {
+            let screenshots = Rc::clone(&screenshots);
+            let root = Rc::clone(&root);
+            lua.globals().set(
+                "screenshot",
+                lua.create_function_mut(move |_, (path,): (String,)| {
+                    screenshots.borrow_mut().push(Self::resolve(&root, &path));
+                    Ok(())
+                })?,
+            )?;
+        }
+
+        {
+            let render_stats = Rc::clone(&render_stats);
+            lua.globals().set(
+                "render_stats",
+                lua.create_function(move |lua, ()| {
+                    let stats = render_stats.get();
+                    let table = lua.create_table()?;
+                    table.set("objects", stats.objects)?;
+                    table.set("drawn", stats.drawn)?;
+                    table.set("culled", stats.culled)?;
+                    table.set("lines", stats.lines)?;
+                    Ok(table)
+                })?,
+            )?;
+        }
+
+        {
+            let body_commands = Rc::clone(&body_commands);
+            lua.globals().set(
+                "apply_force",
+                lua.create_function_mut(move |_, (id, force): (u32, Table)| {
+                    let force = Self::vector_from_table(&force)?;
+                    body_commands
+                        .borrow_mut()
+                        .push(BodyCommand::Force(id, force));
+                    Ok(())
+                })?,
+            )?;
+        }
+
+        {
+            let body_commands = Rc::clone(&body_commands);
+            lua.globals().set(
+                "apply_torque",
+                lua.create_function_mut(move |_, (id, torque): (u32, f32)| {
+                    body_commands
+                        .borrow_mut()
+                        .push(BodyCommand::Torque(id, torque));
+                    Ok(())
+                })?,
+            )?;
+        }
+
+        {
+            let body_commands = Rc::clone(&body_commands);
+            lua.globals().set(
+                "apply_impulse",
+                lua.create_function_mut(move |_, (id, impulse): (u32, Table)| {
+                    let impulse = Self::vector_from_table(&impulse)?;
+                    body_commands
+                        .borrow_mut()
+                        .push(BodyCommand::Impulse(id, impulse));
+                    Ok(())
+                })?,
+            )?;
+        }
+
+        {
+            let body_commands = Rc::clone(&body_commands);
+            lua.globals().set(
+                "set_velocity",
+                lua.create_function_mut(move |_, (id, velocity): (u32, Table)| {
+                    let velocity = Self::vector_from_table(&velocity)?;
+                    body_commands
+                        .borrow_mut()
+                        .push(BodyCommand::Velocity(id, velocity));
+                    Ok(())
+                })?,
+            )?;
+        }
+
+        {
+            let body_commands = Rc::clone(&body_commands);
+            lua.globals().set(
+                "set_angular_velocity",
+                lua.create_function_mut(move |_, (id, angular_velocity): (u32, f32)| {
+                    body_commands
+                        .borrow_mut()
+                        .push(BodyCommand::AngularVelocity(id, angular_velocity));
                     Ok(())
                 })?,
             )?;
         }
 
+        {
+            let objects = Rc::clone(&objects);
+            let spatial = Rc::clone(&spatial);
+            lua.globals().set(
+                "raycast",
+                lua.create_function(
+                    move |lua,
+                          (origin, dir, max_distance, options): (
+                        Table,
+                        Table,
+                        f32,
+                        Option<Table>,
+                    )| {
+                        let origin = Self::vector_from_table(&origin)?;
+                        let dir = Self::vector_from_table(&dir)?;
+                        if !dir.iter().all(|c| c.is_finite()) || dir.norm() <= f32::EPSILON {
+                            return Err(mlua::Error::RuntimeError(
+                                "The direction of the ray is zero".to_string(),
+                            ));
+                        }
+                        if !max_distance.is_finite() || max_distance < 0.0 {
+                            return Err(mlua::Error::RuntimeError(format!(
+                                "The max distance of a ray must be finite and not negative, got {}",
+                                max_distance
+                            )));
+                        }
+                        let exact = match &options {
+                            Some(options) => options.get::<Option<bool>>("exact")?.unwrap_or(false),
+                            None => false,
+                        };
+                        let target = if exact {
+                            RayTarget::Meshes
+                        } else {
+                            RayTarget::Colliders
+                        };
+                        let filter = Self::query_filter(&options)?;
+                        let Some(hit) = query::raycast(
+                            &objects.borrow(),
+                            &spatial.borrow(),
+                            &origin,
+                            &dir,
+                            max_distance,
+                            target,
+                            &filter,
+                        ) else {
+                            return Ok(None);
+                        };
+                        let hit_table = lua.create_table()?;
+                        hit_table.set("id", hit.id)?;
+                        hit_table.set("point", Self::vector_table(lua, &hit.point)?)?;
+                        hit_table.set("normal", Self::vector_table(lua, &hit.normal)?)?;
+                        hit_table.set("distance", hit.distance)?;
+                        Ok(Some(hit_table))
+                    },
+                )?,
+            )?;
+        }
+
+        {
+            let objects = Rc::clone(&objects);
+            let spatial = Rc::clone(&spatial);
+            lua.globals().set(
+                "overlap_sphere",
+                lua.create_function(
+                    move |_, (center, radius, options): (Table, f32, Option<Table>)| {
+                        let center = Self::vector_from_table(&center)?;
+                        let filter = Self::query_filter(&options)?;
+                        Ok(query::overlap_sphere(
+                            &objects.borrow(),
+                            &spatial.borrow(),
+                            &center,
+                            radius,
+                            &filter,
+                        ))
+                    },
+                )?,
+            )?;
+        }
+
+        {
+            let objects = Rc::clone(&objects);
+            let spatial = Rc::clone(&spatial);
+            lua.globals().set(
+                "overlap_box",
+                lua.create_function(
+                    move |_,
+                          (center, half_extents, rotation, options): (
+                        Table,
+                        Table,
+                        Option<f32>,
+                        Option<Table>,
+                    )| {
+                        let center = Self::vector_from_table(&center)?;
+                        let half_extents = Self::vector_from_table(&half_extents)?;
+                        let filter = Self::query_filter(&options)?;
+                        Ok(query::overlap_box(
+                            &objects.borrow(),
+                            &spatial.borrow(),
+                            &center,
+                            &half_extents,
+                            rotation.unwrap_or(0.0),
+                            &filter,
+                        ))
+                    },
+                )?,
+            )?;
+        }
+
+        {
+            let objects = Rc::clone(&objects);
+            let spatial = Rc::clone(&spatial);
+            let gravity = Rc::clone(&gravity);
+            lua.globals().set(
+                "move_and_slide",
+                lua.create_function(
+                    move |lua, (object, motion, options): (Table, Table, Option<Table>)| {
+                        let id: u32 = object.get("id")?;
+                        let position_table: Table = object.get("position")?;
+                        let position = Self::vector_from_table(&position_table)?;
+                        let rotation: f32 = object.get("rotation")?;
+                        let motion = Self::vector_from_table(&motion)?;
+                        let controller = Self::character_controller(options, &gravity.get())?;
+
+                        let objects = objects.borrow();
+                        let index = objects
+                            .iter()
+                            .position(|obj| obj.random_id == id)
+                            .ok_or_else(|| {
+                                mlua::Error::RuntimeError(format!("Unknown object: {}", id))
+                            })?;
+                        let moved = controller.move_and_slide(
+                            &objects,
+                            &spatial.borrow(),
+                            index,
+                            position,
+                            rotation,
+                            &motion,
+                        );
+                        position_table.set("x", moved.position.x)?;
+                        position_table.set("y", moved.position.y)?;
+                        position_table.set("z", moved.position.z)?;
+
+                        let result = lua.create_table()?;
+                        result.set("grounded", moved.grounded)?;
+                        result.set("hits", moved.hits)?;
+                        Ok(result)
+                    },
+                )?,
+            )?;
+        }
+
         // Load and execute the Lua script start
-        let start_script =
-            std::fs::read_to_string("scripting/start.lua").expect("Could not read the Lua script");
+        let start_path = Self::resolve(&root, &scripts.start);
+        let start_script = std::fs::read_to_string(&start_path)
+            .unwrap_or_else(|e| panic!("Could not read the Lua script {}: {}", start_path, e));
         lua.load(&start_script).exec()?;
 
         let start_func: mlua::Function = lua.globals().get("start")?;
         start_func.call(())?;
 
         // Load and execute the Lua script update
-        let update_script =
-            std::fs::read_to_string("scripting/update.lua").expect("Could not read the Lua script");
+        let update_path = Self::resolve(&root, &scripts.update);
+        let update_script = std::fs::read_to_string(&update_path)
+            .unwrap_or_else(|e| panic!("Could not read the Lua script {}: {}", update_path, e));
         lua.load(&update_script).exec()?;
 
         Ok(Self {
             lua,
             objects,
             pending_objects,
+            screenshots,
+            render_stats,
+            sound_system,
+            collisions: RefCell::new(CollisionWorld::new()),
+            spatial,
+            body_commands,
+            gravity,
         })
     }
 
-    fn update_object_with_lua(
-        lua: &Lua,
-        obj: &mut Object3d,
-        dt: f32,
-        inputs: &Input,
-    ) -> LuaResult<()> {
-        let globals = lua.globals();
-        let update_func: mlua::Function = globals.get("update")?;
+    /// Path of a file of the project, absolute paths are kept as they are.
+    fn resolve(root: &Path, path: &str) -> String {
+        root.join(path).to_string_lossy().into_owned()
+    }
+
+    /// Logs an audio failure and turns it into an error that Lua can catch with `pcall`.
+    fn sound_error(e: SoundError) -> mlua::Error {
+        eprintln!("Error en audio: {}", e);
+        mlua::Error::RuntimeError(e.to_string())
+    }
+
+    /// Reads the optional options table of `play_sound`:
+    /// `{loop=, volume=, speed=, bus=, priority=, position={x,y,z}, object=id,
+    ///   falloff=, min_distance=, max_distance=, rolloff=}`.
+    fn play_options(table: Option<Table>) -> LuaResult<PlayOptions> {
+        let mut options = PlayOptions::default();
+        let Some(table) = table else {
+            return Ok(options);
+        };
+        options.looping = table
+            .get::<Option<bool>>("loop")?
+            .unwrap_or(options.looping);
+        options.volume = table
+            .get::<Option<f32>>("volume")?
+            .unwrap_or(options.volume);
+        options.speed = table.get::<Option<f32>>("speed")?.unwrap_or(options.speed);
+        if let Some(bus) = table.get::<Option<String>>("bus")? {
+            options.bus = bus;
+        }
+        options.priority = table
+            .get::<Option<i32>>("priority")?
+            .unwrap_or(options.priority);
+
+        if let Some(position) = table.get::<Option<Table>>("position")? {
+            options.emitter = Some(Emitter::Point(Self::vector_from_table(&position)?));
+        }
+        if let Some(id) = table.get::<Option<u32>>("object")? {
+            options.emitter = Some(Emitter::Object(id));
+        }
+
+        let attenuation = &mut options.attenuation;
+        if let Some(name) = table.get::<Option<String>>("falloff")? {
+            attenuation.falloff = Falloff::from_name(&name).ok_or_else(|| {
+                mlua::Error::RuntimeError(format!("Unknown falloff model: {}", name))
+            })?;
+        }
+        attenuation.min_distance = table
+            .get::<Option<f32>>("min_distance")?
+            .unwrap_or(attenuation.min_distance);
+        attenuation.max_distance = table
+            .get::<Option<f32>>("max_distance")?
+            .unwrap_or(attenuation.max_distance);
+        attenuation.rolloff = table
+            .get::<Option<f32>>("rolloff")?
+            .unwrap_or(attenuation.rolloff);
+        Ok(options)
+    }
+
+    /// Reads the synth part of the `play_tone` table:
+    /// `{wave=, freq=, freq_end=, duration=, duty=, attack=, decay=, sustain=, release=,
+    ///   filter='lowpass'|'highpass', cutoff=, seed=}`.
+    fn tone_spec(table: &Table) -> LuaResult<ToneSpec> {
+        let mut spec = ToneSpec::default();
+        if let Some(name) = table.get::<Option<String>>("wave")? {
+            spec.wave = Waveform::from_name(&name)
+                .ok_or_else(|| mlua::Error::RuntimeError(format!("Unknown waveform: {}", name)))?;
+        }
+        spec.freq = table.get::<Option<f32>>("freq")?.unwrap_or(spec.freq);
+        spec.freq_end = table.get::<Option<f32>>("freq_end")?;
+        spec.duration = table
+            .get::<Option<f32>>("duration")?
+            .unwrap_or(spec.duration);
+        spec.duty = table.get::<Option<f32>>("duty")?.unwrap_or(spec.duty);
+        spec.seed = table.get::<Option<u32>>("seed")?.unwrap_or(spec.seed);
+
+        let envelope = &mut spec.envelope;
+        envelope.attack = table
+            .get::<Option<f32>>("attack")?
+            .unwrap_or(envelope.attack);
+        envelope.decay = table.get::<Option<f32>>("decay")?.unwrap_or(envelope.decay);
+        envelope.sustain = table
+            .get::<Option<f32>>("sustain")?
+            .unwrap_or(envelope.sustain);
+        envelope.release = table
+            .get::<Option<f32>>("release")?
+            .unwrap_or(envelope.release);
+
+        if let Some(name) = table.get::<Option<String>>("filter")? {
+            let cutoff = table.get::<Option<f32>>("cutoff")?.unwrap_or(1000.0);
+            spec.filter = match name.as_str() {
+                "none" => Filter::None,
+                "lowpass" => Filter::LowPass(cutoff),
+                "highpass" => Filter::HighPass(cutoff),
+                _ => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "Unknown filter: {}",
+                        name
+                    )))
+                }
+            };
+        }
+        Ok(spec)
+    }
+
+    /// Reads the `body` option of `new_model`: `true` for the defaults or a table with
+    /// mass, kinematic, gravity_scale, damping, angular_damping, restitution, friction and
+    /// velocity.
+    fn rigid_body(value: Value) -> LuaResult<Option<RigidBody>> {
+        let table = match value {
+            Value::Nil | Value::Boolean(false) => return Ok(None),
+            Value::Boolean(true) => return Ok(Some(RigidBody::default())),
+            Value::Table(table) => table,
+            other => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "Invalid body: {:?}",
+                    other
+                )))
+            }
+        };
+        let mut body = RigidBody::default();
+        let kinematic = table.get::<Option<bool>>("kinematic")?.unwrap_or(false);
+        if kinematic {
+            body.mass = 0.0;
+        } else if let Some(mass) = table.get::<Option<f32>>("mass")? {
+            if !mass.is_finite() || mass <= 0.0 {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "The mass of a body must be a positive number, got {}, use kinematic = true \
+                     for a body that forces do not move",
+                    mass
+                )));
+            }
+            body.mass = mass;
+        }
+        if let Some(gravity_scale) = table.get("gravity_scale")? {
+            body.gravity_scale = gravity_scale;
+        }
+        if let Some(damping) = table.get("damping")? {
+            body.linear_damping = Self::body_coefficient("damping", damping)?;
+        }
+        if let Some(angular_damping) = table.get("angular_damping")? {
+            body.angular_damping = Self::body_coefficient("angular_damping", angular_damping)?;
+        }
+        if let Some(restitution) = table.get("restitution")? {
+            body.restitution = Self::body_coefficient("restitution", restitution)?;
+        }
+        if let Some(friction) = table.get("friction")? {
+            body.friction = Self::body_coefficient("friction", friction)?;
+        }
+        if let Some(velocity) = table.get::<Option<Table>>("velocity")? {
+            body.velocity = Self::vector_from_table(&velocity)?;
+        }
+        Ok(Some(body))
+    }
+
+    /// Checks a damping, restitution or friction of a body, they can not be negative.
+    fn body_coefficient(name: &str, value: f32) -> LuaResult<f32> {
+        if !value.is_finite() || value < 0.0 {
+            return Err(mlua::Error::RuntimeError(format!(
+                "The {} of a body must be a number not below zero, got {}",
+                name, value
+            )));
+        }
+        Ok(value)
+    }
+
+    /// Reads the options of `move_and_slide`: `{step_offset=, slope_limit=degrees, skin=}`.
+    /// Up is against the gravity.
+    fn character_controller(
+        options: Option<Table>,
+        gravity: &Vector3<f32>,
+    ) -> LuaResult<CharacterController> {
+        let mut controller = CharacterController::default();
+        if let Some(up) = (-gravity).try_normalize(f32::EPSILON) {
+            controller.up = up;
+        }
+        let Some(options) = options else {
+            return Ok(controller);
+        };
+        if let Some(step_offset) = options.get("step_offset")? {
+            controller.step_offset = Self::controller_distance("step_offset", step_offset)?;
+        }
+        if let Some(slope_limit) = options.get::<Option<f32>>("slope_limit")? {
+            if !(0.0..90.0).contains(&slope_limit) {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "The slope_limit of move_and_slide must be from 0 to 90 degrees, got {}",
+                    slope_limit
+                )));
+            }
+            controller.slope_limit = slope_limit.to_radians();
+        }
+        if let Some(skin) = options.get("skin")? {
+            controller.skin = Self::controller_distance("skin", skin)?;
+        }
+        Ok(controller)
+    }
+
+    /// Checks the step_offset or skin of `move_and_slide`, they can not be negative.
+    fn controller_distance(name: &str, value: f32) -> LuaResult<f32> {
+        if !value.is_finite() || value < 0.0 {
+            return Err(mlua::Error::RuntimeError(format!(
+                "The {} of move_and_slide must be a number not below zero, got {}",
+                name, value
+            )));
+        }
+        Ok(value)
+    }
+
+    /// Reads the options of the queries: `{ignore=id or {ids}, mask=layers, triggers=}`.
+    fn query_filter(options: &Option<Table>) -> LuaResult<QueryFilter> {
+        let mut filter = QueryFilter::default();
+        let Some(options) = options else {
+            return Ok(filter);
+        };
+        filter.ignore = match options.get::<Value>("ignore")? {
+            Value::Nil => Vec::new(),
+            Value::Table(ids) => ids.sequence_values::<u32>().collect::<LuaResult<_>>()?,
+            Value::Integer(id) => vec![id as u32],
+            Value::Number(id) => vec![id as u32],
+            other => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "Invalid ignore: {:?}",
+                    other
+                )))
+            }
+        };
+        if let Some(mask) = Self::layer_bits(options.get("mask")?)? {
+            filter.mask = mask;
+        }
+        filter.triggers = options
+            .get::<Option<bool>>("triggers")?
+            .unwrap_or(filter.triggers);
+        Ok(filter)
+    }
+
+    /// Reads collision layers, numbered from 1 to 32: a single layer or a list of them.
+    fn layer_bits(value: Value) -> LuaResult<Option<u32>> {
+        let layers: Vec<i64> = match value {
+            Value::Nil => return Ok(None),
+            Value::Integer(layer) => vec![layer],
+            Value::Number(layer) => vec![layer as i64],
+            Value::Table(layers) => layers.sequence_values::<i64>().collect::<LuaResult<_>>()?,
+            other => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "Invalid layers: {:?}",
+                    other
+                )))
+            }
+        };
+        let mut bits = 0;
+        for layer in layers {
+            if !(1..=32).contains(&layer) {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "Invalid layer: {}, layers go from 1 to 32",
+                    layer
+                )));
+            }
+            bits |= 1 << (layer - 1);
+        }
+        Ok(Some(bits))
+    }
+
+    /// The layers of a bit set as the list scripts use.
+    fn layer_list(lua: &Lua, bits: u32) -> LuaResult<Table> {
+        lua.create_sequence_from((1..=32).filter(|layer| bits & (1 << (layer - 1)) != 0))
+    }
+
+    /// Reads `{r=, g=, b=, a=}` or `{r, g, b, a}`, from 0 to 1; the alpha is 1 when missing.
+    fn color_from_table(table: &Table) -> LuaResult<Rgba> {
+        let channel = |name: &str, index: usize, default: Option<f32>| -> LuaResult<f32> {
+            if let Some(value) = table.get::<Option<f32>>(name)? {
+                return Ok(value);
+            }
+            if let Some(value) = table.get::<Option<f32>>(index)? {
+                return Ok(value);
+            }
+            default.ok_or_else(|| {
+                mlua::Error::RuntimeError(format!("The color has no {} channel", name))
+            })
+        };
+        Ok([
+            channel("r", 1, None)?,
+            channel("g", 2, None)?,
+            channel("b", 3, None)?,
+            channel("a", 4, Some(1.0))?,
+        ])
+    }
+
+    fn color_table(lua: &Lua, color: &Rgba) -> LuaResult<Table> {
+        let table = lua.create_table()?;
+        table.set("r", color[0])?;
+        table.set("g", color[1])?;
+        table.set("b", color[2])?;
+        table.set("a", color[3])?;
+        Ok(table)
+    }
+
+    fn vector_table(lua: &Lua, vector: &Vector3<f32>) -> LuaResult<Table> {
+        let table = lua.create_table()?;
+        table.set("x", vector.x)?;
+        table.set("y", vector.y)?;
+        table.set("z", vector.z)?;
+        Ok(table)
+    }
+
+    fn vector_from_table(table: &Table) -> LuaResult<Vector3<f32>> {
+        let x: f32 = table.get("x")?;
+        let y: f32 = table.get("y")?;
+        let z: f32 = table.get("z")?;
+        Ok(Vector3::new(x, y, z))
+    }
+
+    pub fn set_gravity(&self, gravity: Vector3<f32>) {
+        self.gravity.set(gravity);
+    }
+
+    /// Rebuilds the spatial index with cells of another size.
+    pub fn set_cell_size(&self, cell_size: f32) {
+        let mut spatial = SpatialGrid::new(cell_size);
+        spatial.sync(&self.objects.borrow());
+        *self.spatial.borrow_mut() = spatial;
+    }
+
+    /// The spatial index of the objects, as of the end of the last update.
+    pub fn spatial(&self) -> std::cell::Ref<'_, SpatialGrid> {
+        self.spatial.borrow()
+    }
 
+    /// Moves the ears of the positional sounds, usually to the camera.
+    pub fn set_listener(&self, listener: Listener) {
+        if let Err(e) = self.sound_system.set_listener(listener) {
+            eprintln!("Error en audio: {}", e);
+        }
+    }
+
+    /// The table scripts see an object as.
+    fn object_table(lua: &Lua, obj: &Object3d) -> LuaResult<Table> {
         let obj_table = lua.create_table()?;
-        {
-            let pos_table = lua.create_table()?;
-            pos_table.set("x", obj.position.x)?;
-            pos_table.set("y", obj.position.y)?;
-            pos_table.set("z", obj.position.z)?;
-            obj_table.set("position", pos_table)?;
-            obj_table.set("rotation", obj.rotation)?;
-            obj_table.set("name", obj.object_name.clone())?;
-            obj_table.set("id", obj.random_id)?;
-            obj_table.set("render", obj.render)?;
+        obj_table.set("position", Self::vector_table(lua, &obj.position)?)?;
+        obj_table.set("rotation", obj.rotation)?;
+        obj_table.set("name", obj.object_name.clone())?;
+        obj_table.set("id", obj.random_id)?;
+        obj_table.set("render", obj.render)?;
+        obj_table.set("color", Self::color_table(lua, &obj.color)?)?;
+        if obj.collider.is_some() {
+            obj_table.set("trigger", obj.trigger)?;
+            obj_table.set("layer", Self::layer_list(lua, obj.filter.layers)?)?;
+            obj_table.set("mask", Self::layer_list(lua, obj.filter.mask)?)?;
+        }
+        if let Some(body) = &obj.body {
+            obj_table.set("velocity", Self::vector_table(lua, &body.velocity)?)?;
+            obj_table.set("angular_velocity", body.angular_velocity)?;
+        }
+        Ok(obj_table)
+    }
+
+    /// Copies the changes a script made to an object table back to the object.
+    fn apply_object_table(obj: &mut Object3d, updated: &Table) -> LuaResult<()> {
+        let pos_table: Table = updated.get("position")?;
+        obj.position.x = pos_table.get("x")?;
+        obj.position.y = pos_table.get("y")?;
+        obj.position.z = pos_table.get("z")?;
+        obj.rotation = updated.get("rotation")?;
+        obj.render = updated.get("render")?;
+        if let Some(color) = updated.get::<Option<Table>>("color")? {
+            obj.color = Self::color_from_table(&color)?;
+        }
+        if obj.collider.is_some() {
+            if let Some(trigger) = updated.get("trigger")? {
+                obj.trigger = trigger;
+            }
+            if let Some(layers) = Self::layer_bits(updated.get("layer")?)? {
+                obj.filter.layers = layers;
+            }
+            if let Some(mask) = Self::layer_bits(updated.get("mask")?)? {
+                obj.filter.mask = mask;
+            }
         }
+        if let Some(body) = obj.body.as_mut() {
+            if let Some(velocity) = updated.get::<Option<Table>>("velocity")? {
+                body.velocity = Self::vector_from_table(&velocity)?;
+            }
+            if let Some(angular_velocity) = updated.get("angular_velocity")? {
+                body.angular_velocity = angular_velocity;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the `update` of the script on the object at `index`. The objects are not borrowed
+    /// while the script runs, so it can query them.
+    fn update_object_with_lua(&self, index: usize, dt: f32, inputs: &Input) -> LuaResult<()> {
+        let lua = &self.lua;
+        let globals = lua.globals();
+        let update_func: mlua::Function = globals.get("update")?;
+
+        let obj_table = Self::object_table(lua, &self.objects.borrow()[index])?;
 
         let pressing_table = lua.create_table()?;
         for (i, key) in inputs.input.iter().enumerate() {
@@ -130,32 +977,219 @@ impl LuaInt {
         let input_table = lua.create_table()?;
         input_table.set("pressing", pressing_table)?;
         input_table.set("just_pressed", just_pressed_table)?;
+        input_table.set("text", inputs.text.as_str())?;
 
         let updated: Table = update_func.call((dt, obj_table, input_table))?;
+        self.apply_lua_changes(index, &updated)
+    }
 
-        let pos_table: Table = updated.get("position")?;
-        obj.position.x = pos_table.get("x")?;
-        obj.position.y = pos_table.get("y")?;
-        obj.position.z = pos_table.get("z")?;
-        obj.rotation = updated.get("rotation")?;
-        obj.render = updated.get("render")?;
+    /// Applies an object table returned by a script and moves the object in the spatial index,
+    /// so queries later in the same step see it where it is.
+    fn apply_lua_changes(&self, index: usize, updated: &Table) -> LuaResult<()> {
+        let mut objects = self.objects.borrow_mut();
+        Self::apply_object_table(&mut objects[index], updated)?;
+        self.spatial
+            .borrow_mut()
+            .update_object(index, &objects[index]);
+        Ok(())
+    }
+
+    fn apply_body_commands(&self, objects: &mut [Object3d]) {
+        for command in self.body_commands.borrow_mut().drain(..) {
+            let id = match command {
+                BodyCommand::Force(id, _)
+                | BodyCommand::Torque(id, _)
+                | BodyCommand::Impulse(id, _)
+                | BodyCommand::Velocity(id, _)
+                | BodyCommand::AngularVelocity(id, _) => id,
+            };
+            let Some(body) = objects
+                .iter_mut()
+                .find(|obj| obj.random_id == id)
+                .and_then(|obj| obj.body.as_mut())
+            else {
+                eprintln!("Error en fisica: el objeto {} no tiene cuerpo", id);
+                continue;
+            };
+            match command {
+                BodyCommand::Force(_, force) => body.apply_force(force),
+                BodyCommand::Torque(_, torque) => body.apply_torque(torque),
+                BodyCommand::Impulse(_, impulse) => body.apply_impulse(impulse),
+                BodyCommand::Velocity(_, velocity) => body.velocity = velocity,
+                BodyCommand::AngularVelocity(_, angular_velocity) => {
+                    body.angular_velocity = angular_velocity
+                }
+            }
+        }
+    }
 
+    /// Calls the optional global `callback(object, other)` for both objects of every pair.
+    /// Like `update`, the callback is shared by every object, which have no scripts of their
+    /// own, and can return the object to change it.
+    fn call_collision_callbacks(&self, callback: &str, pairs: &[(u32, u32)]) -> LuaResult<()> {
+        let Some(callback) = self.lua.globals().get::<Option<mlua::Function>>(callback)? else {
+            return Ok(());
+        };
+        for &(a, b) in pairs {
+            for (id, other_id) in [(a, b), (b, a)] {
+                let (object, object_table, other_table) = {
+                    let objects = self.objects.borrow();
+                    let object = objects.iter().position(|obj| obj.random_id == id);
+                    let other = objects.iter().position(|obj| obj.random_id == other_id);
+                    // One of them no longer exists
+                    let (Some(object), Some(other)) = (object, other) else {
+                        continue;
+                    };
+                    (
+                        object,
+                        Self::object_table(&self.lua, &objects[object])?,
+                        Self::object_table(&self.lua, &objects[other])?,
+                    )
+                };
+                let updated: Option<Table> = callback.call((object_table, other_table))?;
+                if let Some(updated) = updated {
+                    self.apply_lua_changes(object, &updated)?;
+                }
+            }
+        }
         Ok(())
     }
 
     pub fn update(&self, dt: f32, inputs: &Input) -> LuaResult<()> {
         let lua = &self.lua;
-        {
-            let mut objects = self.objects.borrow_mut();
-            for obj in objects.iter_mut() {
-                Self::update_object_with_lua(lua, obj, dt, inputs)?;
+        // Optional callback for text fields, called once per frame with the typed text
+        if !inputs.text.is_empty() {
+            if let Some(on_text_input) = lua
+                .globals()
+                .get::<Option<mlua::Function>>("on_text_input")?
+            {
+                on_text_input.call::<()>(inputs.text.as_str())?;
             }
         }
+        // New objects wait in `pending_objects`, so the list keeps its length
+        let count = self.objects.borrow().len();
+        for index in 0..count {
+            self.objects.borrow_mut()[index].save_previous_transform();
+            self.update_object_with_lua(index, dt, inputs)?;
+        }
         {
             let mut objects = self.objects.borrow_mut();
             let mut pending = self.pending_objects.borrow_mut();
             objects.append(&mut pending);
         }
+        {
+            let mut objects = self.objects.borrow_mut();
+            self.apply_body_commands(&mut objects);
+            physics::integrate(&mut objects, &self.gravity.get(), dt);
+        }
+        self.spatial.borrow_mut().sync(&self.objects.borrow());
+        let events = self
+            .collisions
+            .borrow_mut()
+            .update(&self.objects.borrow(), &self.spatial.borrow());
+        physics::resolve_contacts(&mut self.objects.borrow_mut(), &events.contacts);
+        self.spatial.borrow_mut().sync(&self.objects.borrow());
+        self.call_collision_callbacks("on_collision_enter", &events.enter)?;
+        self.call_collision_callbacks("on_collision_stay", &events.stay)?;
+        self.call_collision_callbacks("on_collision_exit", &events.exit)?;
+        self.call_collision_callbacks("on_trigger_enter", &events.trigger_enter)?;
+        self.call_collision_callbacks("on_trigger_exit", &events.trigger_exit)?;
+        if let Err(e) = self.sound_system.update(dt, &self.objects.borrow()) {
+            eprintln!("Error en audio: {}", e);
+        }
         Ok(())
     }
 }
+
+impl UserData for SoundHandle {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("stop", |_, handle, ()| {
+            handle.stop();
+            Ok(())
+        });
+        methods.add_method("pause", |_, handle, ()| {
+            handle.pause();
+            Ok(())
+        });
+        methods.add_method("resume", |_, handle, ()| {
+            handle.resume();
+            Ok(())
+        });
+        methods.add_method("set_volume", |_, handle, volume: f32| {
+            handle.set_volume(volume);
+            Ok(())
+        });
+        methods.add_method("set_speed", |_, handle, speed: f32| {
+            handle.set_speed(speed);
+            Ok(())
+        });
+        methods.add_method("set_position", |_, handle, position: Table| {
+            handle.set_emitter(Emitter::Point(LuaInt::vector_from_table(&position)?));
+            Ok(())
+        });
+        methods.add_method("follow", |_, handle, id: u32| {
+            handle.set_emitter(Emitter::Object(id));
+            Ok(())
+        });
+        methods.add_method("is_playing", |_, handle, ()| Ok(handle.is_playing()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(lua: &Lua, options: &str) -> LuaResult<Option<RigidBody>> {
+        LuaInt::rigid_body(lua.load(options).eval()?)
+    }
+
+    #[test]
+    fn bodies_reject_invalid_values() {
+        let lua = Lua::new();
+        assert!(body(&lua, "nil").unwrap().is_none());
+        let parsed = body(&lua, "{ mass = 2, damping = 0.5, friction = 0 }")
+            .unwrap()
+            .unwrap();
+        assert_eq!(parsed.mass, 2.0);
+        assert_eq!(parsed.linear_damping, 0.5);
+        assert_eq!(parsed.friction, 0.0);
+        let kinematic = body(&lua, "{ kinematic = true }").unwrap().unwrap();
+        assert_eq!(kinematic.inverse_mass(), 0.0);
+
+        for options in [
+            "{ mass = 0 }",
+            "{ mass = -1 }",
+            "{ mass = 0/0 }",
+            "{ damping = -0.1 }",
+            "{ angular_damping = 0/0 }",
+            "{ restitution = -1 }",
+            "{ friction = 0/0 }",
+        ] {
+            assert!(body(&lua, options).is_err(), "{}", options);
+        }
+    }
+
+    #[test]
+    fn character_controllers_reject_invalid_values() {
+        let lua = Lua::new();
+        let gravity = Vector3::new(0.0, 9.81, 0.0);
+        let controller =
+            |options: &str| LuaInt::character_controller(Some(lua.load(options).eval()?), &gravity);
+        let parsed = controller("{ step_offset = 0.5, slope_limit = 30, skin = 0 }").unwrap();
+        assert_eq!(parsed.step_offset, 0.5);
+        assert_eq!(parsed.slope_limit, 30f32.to_radians());
+        assert_eq!(parsed.up, Vector3::new(0.0, -1.0, 0.0));
+
+        for options in [
+            "{ step_offset = -0.1 }",
+            "{ step_offset = 0/0 }",
+            "{ skin = -0.01 }",
+            "{ skin = 0/0 }",
+            "{ slope_limit = -1 }",
+            "{ slope_limit = 90 }",
+            "{ slope_limit = 0/0 }",
+        ] {
+            assert!(controller(options).is_err(), "{}", options);
+        }
+    }
+}
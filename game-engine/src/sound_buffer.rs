@@ -0,0 +1,91 @@
+use rodio::source::UniformSourceIterator;
+use rodio::{Decoder, Source};
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::sound_system::SoundError;
+
+/// A sound decoded once to samples, shared by every voice that plays it.
+pub struct DecodedSound {
+    channels: u16,
+    sample_rate: u32,
+    samples: Vec<f32>,
+}
+
+impl DecodedSound {
+    /// Decodes a whole file (wav, ogg, flac or mp3) kept in memory.
+    pub fn decode(bytes: Vec<u8>) -> Result<Self, SoundError> {
+        let decoder = Decoder::new(Cursor::new(bytes))?;
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        // Some formats can change their parameters midway, keep the ones of the start
+        let samples: Vec<f32> =
+            UniformSourceIterator::new(decoder, channels, sample_rate).collect();
+        Ok(DecodedSound {
+            channels,
+            sample_rate,
+            samples,
+        })
+    }
+
+    pub fn duration(&self) -> Duration {
+        let frames = self.samples.len() / self.channels.max(1) as usize;
+        Duration::from_secs_f64(frames as f64 / self.sample_rate as f64)
+    }
+}
+
+/// Plays a `DecodedSound` without copying its samples.
+pub struct SharedSamples {
+    sound: Arc<DecodedSound>,
+    position: usize,
+    looping: bool,
+}
+
+impl SharedSamples {
+    pub fn new(sound: Arc<DecodedSound>, looping: bool) -> Self {
+        SharedSamples {
+            sound,
+            position: 0,
+            looping,
+        }
+    }
+}
+
+impl Iterator for SharedSamples {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.position >= self.sound.samples.len() {
+            if !self.looping || self.sound.samples.is_empty() {
+                return None;
+            }
+            self.position = 0;
+        }
+        let sample = self.sound.samples[self.position];
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+impl Source for SharedSamples {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.sound.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sound.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        if self.looping {
+            None
+        } else {
+            Some(self.sound.duration())
+        }
+    }
+}
@@ -1,48 +1,483 @@
-use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink};
+use na::Vector3;
+use rodio::{Sink, Source};
 use std::collections::HashMap;
 use std::fs;
-use std::io::{BufReader, Cursor};
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::audio_backend::{AudioBackend, BackendKind, PlayKind, PlayedSound};
+use crate::mixer::{Mixer, MUSIC_BUS, SFX_BUS};
+use crate::music::MusicChannel;
+use crate::object3d::Object3d;
+use crate::sound_buffer::{DecodedSound, SharedSamples};
+use crate::spatial_audio::{Attenuation, Emitter, Listener, Panned, SpatialState};
+use crate::synth::{Tone, ToneSpec};
+
+pub type SoundError = Box<dyn std::error::Error + Send + Sync>;
+
+// Voices that can play at the same time before the lowest priority ones are stolen
+const DEFAULT_MAX_VOICES: usize = 32;
+
+/// How a sound is played, the defaults play it once at normal volume and speed.
+pub struct PlayOptions {
+    pub looping: bool,
+    pub volume: f32,
+    // 1.0 is the normal speed, it also changes the pitch
+    pub speed: f32,
+    // When set the sound is panned and attenuated relative to the listener
+    pub emitter: Option<Emitter>,
+    pub attenuation: Attenuation,
+    pub bus: String,
+    // When all the voices are in use, a sound can only replace one with a lower or equal priority
+    pub priority: i32,
+}
+
+impl Default for PlayOptions {
+    fn default() -> Self {
+        PlayOptions {
+            looping: false,
+            volume: 1.0,
+            speed: 1.0,
+            emitter: None,
+            attenuation: Attenuation::default(),
+            bus: SFX_BUS.to_string(),
+            priority: 0,
+        }
+    }
+}
+
+/// Controls a sound after it started playing.
+/// Handles of sounds dropped because all the voices were busy, or of a backend without sinks,
+/// have no sink and ignore every call.
+#[derive(Clone)]
+pub struct SoundHandle {
+    sink: Option<Arc<Sink>>,
+    spatial: Option<Arc<SpatialState>>,
+    // f32 bits of the volume set by the script, the bus gain is applied on top of it
+    volume: Arc<AtomicU32>,
+}
+
+impl SoundHandle {
+    fn silent() -> Self {
+        SoundHandle {
+            sink: None,
+            spatial: None,
+            volume: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    pub fn stop(&self) {
+        if let Some(sink) = &self.sink {
+            sink.stop();
+        }
+    }
+
+    pub fn pause(&self) {
+        if let Some(sink) = &self.sink {
+            sink.pause();
+        }
+    }
+
+    pub fn resume(&self) {
+        if let Some(sink) = &self.sink {
+            sink.play();
+        }
+    }
+
+    /// The new volume is applied on the next `SoundSystem::update`.
+    pub fn set_volume(&self, volume: f32) {
+        self.volume.store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_speed(&self, speed: f32) {
+        if let Some(sink) = &self.sink {
+            sink.set_speed(speed);
+        }
+    }
+
+    /// Moves a positional sound, it does nothing on sounds played without a position or object.
+    pub fn set_emitter(&self, emitter: Emitter) {
+        if let Some(spatial) = &self.spatial {
+            spatial.set_emitter(emitter);
+        }
+    }
+
+    /// True while the sound has not finished and is not paused.
+    pub fn is_playing(&self) -> bool {
+        match &self.sink {
+            Some(sink) => !sink.empty() && !sink.is_paused(),
+            None => false,
+        }
+    }
+}
+
+/// A sound that is playing, kept alive by the sound system until it finishes.
+struct Voice {
+    handle: SoundHandle,
+    bus: String,
+    priority: i32,
+}
+
+impl Voice {
+    fn sink(&self) -> &Sink {
+        self.handle
+            .sink
+            .as_ref()
+            .expect("Voices always have a sink")
+    }
+
+    fn finished(&self) -> bool {
+        self.sink().empty()
+    }
+
+    fn apply_volume(&self, mixer: &Mixer) {
+        let volume = f32::from_bits(self.handle.volume.load(Ordering::Relaxed));
+        self.sink().set_volume(volume * mixer.gain(&self.bus));
+    }
+}
+
+struct VoicePool {
+    // In the order they started, the oldest first
+    voices: Vec<Voice>,
+    max_voices: usize,
+}
+
+impl VoicePool {
+    /// Index of the oldest of the lowest priority voices, the first to be stolen.
+    fn lowest_priority(&self) -> Option<usize> {
+        self.voices
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, voice)| voice.priority)
+            .map(|(i, _)| i)
+    }
+}
 
 pub struct SoundSystem {
-    _stream: OutputStream,
-    stream_handle: OutputStreamHandle,
-    cache: Mutex<HashMap<String, Arc<Vec<u8>>>>,
+    backend: Box<dyn AudioBackend>,
+    // Sounds are decoded the first time they are played and the samples shared by every voice
+    cache: Mutex<HashMap<String, Arc<DecodedSound>>>,
+    music: Mutex<MusicChannel>,
+    mixer: Mutex<Mixer>,
+    voices: Mutex<VoicePool>,
+    listener: Mutex<Listener>,
+    // Positions of the objects on the last update, used by the sounds that follow them
+    object_positions: Mutex<HashMap<u32, Vector3<f32>>>,
+}
+
+/// Locks one of the mutexes of the sound system, turning a poisoned lock into an error.
+fn lock<'a, T>(mutex: &'a Mutex<T>, name: &str) -> Result<MutexGuard<'a, T>, SoundError> {
+    mutex
+        .lock()
+        .map_err(|_| format!("The {} is poisoned", name).into())
 }
 
 impl SoundSystem {
-    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let (stream, stream_handle) = OutputStream::try_default()?;
-        Ok(SoundSystem {
-            _stream: stream,
-            stream_handle,
+    pub fn new(kind: BackendKind) -> Result<Self, SoundError> {
+        Ok(Self::with_backend(kind.create()?))
+    }
+
+    pub fn with_backend(backend: Box<dyn AudioBackend>) -> Self {
+        SoundSystem {
+            backend,
             cache: Mutex::new(HashMap::new()),
-        })
-    }
-    pub fn play_sound(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let sound_data = {
-            let mut cache = self.cache.lock().unwrap();
-            cache
-                .entry(path.to_owned())
-                .or_insert_with(|| {
-                    let bytes = fs::read(path).unwrap_or_else(|err| {
-                        panic!("Error al leer el archivo {}: {:?}", path, err)
-                    });
-                    Arc::new(bytes)
-                })
-                .clone()
-        };
+            music: Mutex::new(MusicChannel::new()),
+            mixer: Mutex::new(Mixer::new()),
+            voices: Mutex::new(VoicePool {
+                voices: Vec::new(),
+                max_voices: DEFAULT_MAX_VOICES,
+            }),
+            listener: Mutex::new(Listener::default()),
+            object_positions: Mutex::new(HashMap::new()),
+        }
+    }
 
-        let stream_handle = self.stream_handle.clone();
-        thread::spawn(move || {
-            let cursor = Cursor::new(sound_data.as_ref().to_owned());
-            let decoder = Decoder::new(BufReader::new(cursor)).unwrap();
-            let sink = Sink::try_new(&stream_handle).unwrap();
-            sink.append(decoder);
-            sink.sleep_until_end();
-        });
+    pub fn backend_name(&self) -> &'static str {
+        self.backend.name()
+    }
 
+    pub fn set_listener(&self, listener: Listener) -> Result<(), SoundError> {
+        *lock(&self.listener, "listener")? = listener;
         Ok(())
     }
+
+    /// Must be called once per frame. It drives the music fades, applies the bus volumes,
+    /// moves the positional sounds and frees the voices that finished.
+    pub fn update(&self, dt: f32, objects: &[Object3d]) -> Result<(), SoundError> {
+        self.backend.advance(dt);
+        let mixer = lock(&self.mixer, "mixer")?;
+        lock(&self.music, "music channel")?.update(
+            dt,
+            mixer.gain(MUSIC_BUS),
+            self.backend.as_ref(),
+        );
+
+        let listener = *lock(&self.listener, "listener")?;
+        let mut object_positions = lock(&self.object_positions, "object positions")?;
+        object_positions.clear();
+        for obj in objects {
+            object_positions.insert(obj.random_id, obj.position);
+        }
+
+        let mut pool = lock(&self.voices, "voice pool")?;
+        pool.voices.retain(|voice| !voice.finished());
+        for voice in pool.voices.iter() {
+            voice.apply_volume(&mixer);
+            if let Some(spatial) = &voice.handle.spatial {
+                spatial.update(&listener, &object_positions);
+            }
+        }
+        Ok(())
+    }
+
+    /// Streams a music track, crossfading from the current one during `fade` seconds.
+    pub fn play_music(&self, path: &str, looping: bool, fade: f32) -> Result<(), SoundError> {
+        lock(&self.music, "music channel")?.play(path, looping, fade, self.backend.as_ref())
+    }
+
+    pub fn stop_music(&self, fade: f32) -> Result<(), SoundError> {
+        lock(&self.music, "music channel")?.stop(fade);
+        Ok(())
+    }
+
+    pub fn set_bus_volume(&self, bus: &str, volume: f32) -> Result<(), SoundError> {
+        lock(&self.mixer, "mixer")?.set_volume(bus, volume);
+        Ok(())
+    }
+
+    pub fn bus_volume(&self, bus: &str) -> Result<f32, SoundError> {
+        Ok(lock(&self.mixer, "mixer")?.volume(bus))
+    }
+
+    pub fn set_bus_muted(&self, bus: &str, muted: bool) -> Result<(), SoundError> {
+        lock(&self.mixer, "mixer")?.set_muted(bus, muted);
+        Ok(())
+    }
+
+    /// Lowering the limit stops the lowest priority voices over it right away.
+    pub fn set_max_voices(&self, max_voices: usize) -> Result<(), SoundError> {
+        let mut pool = lock(&self.voices, "voice pool")?;
+        pool.max_voices = max_voices.max(1);
+        pool.voices.retain(|voice| !voice.finished());
+        while pool.voices.len() > pool.max_voices {
+            let Some(i) = pool.lowest_priority() else {
+                break;
+            };
+            pool.voices.remove(i).handle.stop();
+        }
+        Ok(())
+    }
+
+    pub fn play_sound(&self, path: &str, options: &PlayOptions) -> Result<SoundHandle, SoundError> {
+        let sound = self.load(path)?;
+        self.record(PlayKind::Sound, path, options);
+        self.start_voice(SharedSamples::new(sound, options.looping), options)
+    }
+
+    /// Plays a synthesized sound, `options.looping` repeats it until it is stopped.
+    pub fn play_tone(
+        &self,
+        spec: ToneSpec,
+        options: &PlayOptions,
+    ) -> Result<SoundHandle, SoundError> {
+        let name = format!("tone:{:?}:{}", spec.wave, spec.freq).to_lowercase();
+        self.record(PlayKind::Tone, &name, options);
+        if options.looping {
+            self.start_voice(Tone::new(spec).repeat_infinite(), options)
+        } else {
+            self.start_voice(Tone::new(spec), options)
+        }
+    }
+
+    fn record(&self, kind: PlayKind, path: &str, options: &PlayOptions) {
+        self.backend.record(&PlayedSound {
+            kind,
+            path: path.to_owned(),
+            bus: options.bus.clone(),
+            volume: options.volume,
+            looping: options.looping,
+            positional: options.emitter.is_some(),
+        });
+    }
+
+    /// Takes a voice from the pool and plays the source on it.
+    fn start_voice<S>(&self, source: S, options: &PlayOptions) -> Result<SoundHandle, SoundError>
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        let bus_gain = lock(&self.mixer, "mixer")?.gain(&options.bus);
+        let mut pool = lock(&self.voices, "voice pool")?;
+        pool.voices.retain(|voice| !voice.finished());
+        if pool.voices.len() >= pool.max_voices {
+            // Steal the oldest of the lowest priority voices, unless the new sound matters less
+            let stolen = pool
+                .lowest_priority()
+                .filter(|&i| pool.voices[i].priority <= options.priority);
+            match stolen {
+                Some(i) => pool.voices.remove(i).handle.stop(),
+                None => return Ok(SoundHandle::silent()),
+            }
+        }
+
+        let Some(sink) = self.backend.new_sink()? else {
+            return Ok(SoundHandle::silent());
+        };
+        let sink = Arc::new(sink);
+        sink.set_speed(options.speed);
+        sink.set_volume(options.volume * bus_gain);
+
+        let spatial = match options.emitter {
+            Some(emitter) => {
+                let spatial = Arc::new(SpatialState::new(emitter, options.attenuation));
+                // Compute the gains now so the start of the sound is not silent
+                let listener = *lock(&self.listener, "listener")?;
+                let object_positions = lock(&self.object_positions, "object positions")?;
+                spatial.update(&listener, &object_positions);
+                sink.append(Panned::new(source, spatial.clone()));
+                Some(spatial)
+            }
+            None => {
+                sink.append(source);
+                None
+            }
+        };
+
+        let voice = Voice {
+            handle: SoundHandle {
+                sink: Some(sink),
+                spatial,
+                volume: Arc::new(AtomicU32::new(options.volume.to_bits())),
+            },
+            bus: options.bus.clone(),
+            priority: options.priority,
+        };
+        let handle = voice.handle.clone();
+        pool.voices.push(voice);
+        Ok(handle)
+    }
+
+    /// Returns the decoded samples of the file, reading and decoding it only the first time.
+    fn load(&self, path: &str) -> Result<Arc<DecodedSound>, SoundError> {
+        let mut cache = lock(&self.cache, "sound cache")?;
+        if let Some(sound) = cache.get(path) {
+            return Ok(sound.clone());
+        }
+        let bytes =
+            fs::read(path).map_err(|e| format!("Could not read the sound {}: {}", path, e))?;
+        let sound = DecodedSound::decode(bytes)
+            .map_err(|e| format!("Could not decode the sound {}: {}", path, e))?;
+        let sound = Arc::new(sound);
+        cache.insert(path.to_owned(), sound.clone());
+        Ok(sound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_backend::{NullBackend, PlayLog};
+
+    const BLASTER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/audio/blaster.wav");
+
+    fn null_system() -> (SoundSystem, PlayLog) {
+        let backend = NullBackend::new();
+        let log = backend.log();
+        (SoundSystem::with_backend(Box::new(backend)), log)
+    }
+
+    // A looping sound, it plays until it is stopped
+    fn looping(priority: i32) -> PlayOptions {
+        PlayOptions {
+            looping: true,
+            priority,
+            ..PlayOptions::default()
+        }
+    }
+
+    #[test]
+    fn sounds_play_until_they_end_or_are_stopped() {
+        let (sounds, log) = null_system();
+        let sound = sounds.play_sound(BLASTER, &PlayOptions::default()).unwrap();
+        let long = sounds.play_sound(BLASTER, &looping(0)).unwrap();
+        assert!(sound.is_playing() && long.is_playing());
+
+        long.stop();
+        sounds.update(0.1, &[]).unwrap();
+        assert!(!long.is_playing());
+        assert!(sound.is_playing());
+
+        for _ in 0..100 {
+            sounds.update(0.1, &[]).unwrap();
+        }
+        assert!(!sound.is_playing());
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].kind, PlayKind::Sound);
+        assert_eq!(log[0].path, BLASTER);
+    }
+
+    #[test]
+    fn paused_sounds_are_not_playing() {
+        let (sounds, _) = null_system();
+        let sound = sounds.play_sound(BLASTER, &looping(0)).unwrap();
+        sound.pause();
+        sounds.update(0.1, &[]).unwrap();
+        assert!(!sound.is_playing());
+        sound.resume();
+        assert!(sound.is_playing());
+    }
+
+    #[test]
+    fn full_pools_steal_the_lowest_priority_voice() {
+        let (sounds, _) = null_system();
+        sounds.set_max_voices(2).unwrap();
+        let low = sounds.play_sound(BLASTER, &looping(0)).unwrap();
+        let high = sounds.play_sound(BLASTER, &looping(5)).unwrap();
+
+        let newer = sounds.play_sound(BLASTER, &looping(1)).unwrap();
+        sounds.update(0.1, &[]).unwrap();
+        assert!(!low.is_playing());
+        assert!(high.is_playing() && newer.is_playing());
+
+        // Nothing plays under the lowest priority in use
+        let dropped = sounds.play_sound(BLASTER, &looping(0)).unwrap();
+        assert!(!dropped.is_playing());
+        assert!(high.is_playing() && newer.is_playing());
+    }
+
+    #[test]
+    fn lowering_the_limit_stops_the_lowest_priority_voices() {
+        let (sounds, _) = null_system();
+        let handles: Vec<SoundHandle> = [2, 0, 1, 0]
+            .into_iter()
+            .map(|p| sounds.play_sound(BLASTER, &looping(p)).unwrap())
+            .collect();
+        sounds.set_max_voices(2).unwrap();
+        sounds.update(0.1, &[]).unwrap();
+        let playing: Vec<bool> = handles.iter().map(SoundHandle::is_playing).collect();
+        assert_eq!(playing, vec![true, false, true, false]);
+
+        // The pool is full, so a new sound still has to steal
+        let newer = sounds.play_sound(BLASTER, &looping(1)).unwrap();
+        sounds.update(0.1, &[]).unwrap();
+        assert!(newer.is_playing());
+        assert!(handles[0].is_playing() && !handles[2].is_playing());
+    }
+
+    #[test]
+    fn finished_voices_are_free_again() {
+        let (sounds, _) = null_system();
+        sounds.set_max_voices(1).unwrap();
+        let options = PlayOptions {
+            priority: 5,
+            ..PlayOptions::default()
+        };
+        sounds.play_sound(BLASTER, &options).unwrap();
+        for _ in 0..100 {
+            sounds.update(0.1, &[]).unwrap();
+        }
+        let next = sounds.play_sound(BLASTER, &looping(0)).unwrap();
+        assert!(next.is_playing());
+    }
 }
@@ -0,0 +1,531 @@
+use na::Vector3;
+use std::collections::HashMap;
+
+use crate::collision::Bounds;
+use crate::object3d::Object3d;
+
+type Cell = (i32, i32, i32);
+
+// Objects covering more cells than this are kept in a list that every query checks, so a huge
+// box over small cells can not fill the grid
+const MAX_CELLS_PER_OBJECT: u64 = 4096;
+
+struct Entry {
+    bounds: Bounds,
+    // Range of cells the bounds cover, both ends included
+    min: Cell,
+    max: Cell,
+    // Listed in `oversized` instead of in its cells
+    oversized: bool,
+}
+
+/// Uniform grid over the rendered objects, by their index in the object list. Each object is
+/// listed in every cell its box touches, so finding what is near something only looks at a few
+/// cells instead of every object.
+pub struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<Cell, Vec<usize>>,
+    entries: Vec<Option<Entry>>,
+    // Objects over `MAX_CELLS_PER_OBJECT` cells
+    oversized: Vec<usize>,
+    // Box around every entry, rays are clipped to it
+    extent: Option<Bounds>,
+}
+
+impl SpatialGrid {
+    /// `cell_size` works best around the size of the usual object.
+    pub fn new(cell_size: f32) -> Self {
+        SpatialGrid {
+            cell_size: cell_size.max(f32::EPSILON),
+            cells: HashMap::new(),
+            entries: Vec::new(),
+            oversized: Vec::new(),
+            extent: None,
+        }
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    /// Number of objects in the grid.
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(Option::is_none)
+    }
+
+    fn cell_of(&self, point: &Vector3<f32>) -> Cell {
+        (
+            (point.x / self.cell_size).floor() as i32,
+            (point.y / self.cell_size).floor() as i32,
+            (point.z / self.cell_size).floor() as i32,
+        )
+    }
+
+    /// Number of cells from `min` to `max`, both included.
+    fn cell_count(min: Cell, max: Cell) -> u64 {
+        let span = |min: i32, max: i32| (max as i64 - min as i64 + 1).max(0) as u64;
+        span(min.0, max.0)
+            .saturating_mul(span(min.1, max.1))
+            .saturating_mul(span(min.2, max.2))
+    }
+
+    fn cells_between(min: Cell, max: Cell) -> impl Iterator<Item = Cell> {
+        (min.0..=max.0).flat_map(move |x| {
+            (min.1..=max.1).flat_map(move |y| (min.2..=max.2).map(move |z| (x, y, z)))
+        })
+    }
+
+    /// Moves every object to the cells of its current transform, adding the new ones and
+    /// dropping the hidden ones. Objects whose cells did not change are not touched.
+    pub fn sync(&mut self, objects: &[Object3d]) {
+        for index in objects.len()..self.entries.len() {
+            self.remove(index);
+        }
+        self.entries.truncate(objects.len());
+        for (index, obj) in objects.iter().enumerate() {
+            self.update_object(index, obj);
+        }
+        // Recomputed so it shrinks when objects leave
+        self.extent = self
+            .entries
+            .iter()
+            .flatten()
+            .map(|entry| entry.bounds)
+            .reduce(|a, b| a.union(&b));
+    }
+
+    /// Moves one object to the cells of its current transform, call it after moving it.
+    pub fn update_object(&mut self, index: usize, obj: &Object3d) {
+        if index >= self.entries.len() {
+            self.entries.resize_with(index + 1, || None);
+        }
+        if !obj.render {
+            self.remove(index);
+            return;
+        }
+        let bounds = obj.bounds();
+        let (min, max) = (self.cell_of(&bounds.min), self.cell_of(&bounds.max));
+        self.extent = Some(match self.extent {
+            Some(extent) => extent.union(&bounds),
+            None => bounds,
+        });
+        if let Some(entry) = self.entries[index].as_mut() {
+            if entry.min == min && entry.max == max {
+                entry.bounds = bounds;
+                return;
+            }
+        }
+        self.remove(index);
+        let oversized = Self::cell_count(min, max) > MAX_CELLS_PER_OBJECT;
+        if oversized {
+            self.oversized.push(index);
+        } else {
+            for cell in Self::cells_between(min, max) {
+                self.cells.entry(cell).or_default().push(index);
+            }
+        }
+        self.entries[index] = Some(Entry {
+            bounds,
+            min,
+            max,
+            oversized,
+        });
+    }
+
+    fn remove(&mut self, index: usize) {
+        let Some(entry) = self.entries.get_mut(index).and_then(Option::take) else {
+            return;
+        };
+        if entry.oversized {
+            self.oversized.retain(|&i| i != index);
+            return;
+        }
+        for cell in Self::cells_between(entry.min, entry.max) {
+            if let Some(indices) = self.cells.get_mut(&cell) {
+                indices.retain(|&i| i != index);
+                if indices.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+    /// The box of an object as it was last synced.
+    pub fn bounds(&self, index: usize) -> Option<&Bounds> {
+        self.entries.get(index)?.as_ref().map(|entry| &entry.bounds)
+    }
+
+    /// Indices of the objects whose box overlaps `bounds`, sorted.
+    pub fn query_bounds(&self, bounds: &Bounds) -> Vec<usize> {
+        let Some(extent) = &self.extent else {
+            return Vec::new();
+        };
+        if !extent.overlaps(bounds) {
+            return Vec::new();
+        }
+        // No need to look at the empty space around the objects
+        let (min, max) = (
+            self.cell_of(&bounds.min.sup(&extent.min)),
+            self.cell_of(&bounds.max.inf(&extent.max)),
+        );
+        let range = Self::cell_count(min, max);
+
+        let mut indices: Vec<usize> = Vec::new();
+        let mut add = |cell_indices: &Vec<usize>| {
+            indices.extend(cell_indices.iter().copied().filter(|&i| {
+                self.bounds(i)
+                    .is_some_and(|object_bounds| object_bounds.overlaps(bounds))
+            }));
+        };
+        add(&self.oversized);
+        // A huge box over a sparse grid is cheaper to check cell by cell
+        if range > self.cells.len() as u64 {
+            let inside = |cell: &Cell| {
+                (min.0..=max.0).contains(&cell.0)
+                    && (min.1..=max.1).contains(&cell.1)
+                    && (min.2..=max.2).contains(&cell.2)
+            };
+            for (_, cell_indices) in self.cells.iter().filter(|(cell, _)| inside(cell)) {
+                add(cell_indices);
+            }
+        } else {
+            for cell in Self::cells_between(min, max) {
+                if let Some(cell_indices) = self.cells.get(&cell) {
+                    add(cell_indices);
+                }
+            }
+        }
+        indices.sort_unstable();
+        indices.dedup();
+        indices
+    }
+
+    /// Pairs of objects whose boxes overlap, as indices with the lowest first, sorted so the
+    /// collisions always come in the same order.
+    pub fn pairs(&self) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        for indices in self.cells.values() {
+            for (n, &a) in indices.iter().enumerate() {
+                for &b in &indices[n + 1..] {
+                    let overlaps = match (self.bounds(a), self.bounds(b)) {
+                        (Some(bounds_a), Some(bounds_b)) => bounds_a.overlaps(bounds_b),
+                        _ => false,
+                    };
+                    if overlaps {
+                        pairs.push((a.min(b), a.max(b)));
+                    }
+                }
+            }
+        }
+        for &a in &self.oversized {
+            let Some(bounds_a) = self.bounds(a) else {
+                continue;
+            };
+            for (b, entry) in self.entries.iter().enumerate() {
+                if entry
+                    .as_ref()
+                    .is_some_and(|entry| b != a && entry.bounds.overlaps(bounds_a))
+                {
+                    pairs.push((a.min(b), a.max(b)));
+                }
+            }
+        }
+        // Objects sharing several cells are found once per cell
+        pairs.sort_unstable();
+        pairs.dedup();
+        pairs
+    }
+
+    /// Walks the cells along a ray in order, with a 3D digital differential analyzer.
+    /// `visit` gets the objects of each cell that has some and the distance where the ray leaves
+    /// the cell; it returns true to stop. Objects too big for the cells are visited first, with
+    /// the distance where the ray enters the grid. `dir` must be normalized. Nothing is visited
+    /// when `max_distance` is negative or NaN.
+    pub fn traverse_ray(
+        &self,
+        origin: &Vector3<f32>,
+        dir: &Vector3<f32>,
+        max_distance: f32,
+        mut visit: impl FnMut(&[usize], f32) -> bool,
+    ) {
+        // A NaN end would never be reached and the walk would not stop
+        if max_distance.is_nan() || max_distance < 0.0 {
+            return;
+        }
+        let Some((near, far)) = self
+            .extent
+            .and_then(|extent| extent.ray_interval(origin, dir, max_distance))
+        else {
+            return;
+        };
+        if !self.oversized.is_empty() && visit(&self.oversized, near) {
+            return;
+        }
+        let start = origin + dir * near;
+        let start_cell = self.cell_of(&start);
+        let mut cell = [start_cell.0, start_cell.1, start_cell.2];
+        let mut step = [0; 3];
+        // Distance along the ray to the next cell boundary on each axis, and between boundaries
+        let mut next = [f32::INFINITY; 3];
+        let mut delta = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            if dir[axis] > 0.0 {
+                step[axis] = 1;
+                let boundary = (cell[axis] + 1) as f32 * self.cell_size;
+                next[axis] = near + (boundary - start[axis]) / dir[axis];
+                delta[axis] = self.cell_size / dir[axis];
+            } else if dir[axis] < 0.0 {
+                step[axis] = -1;
+                let boundary = cell[axis] as f32 * self.cell_size;
+                next[axis] = near + (boundary - start[axis]) / dir[axis];
+                delta[axis] = -self.cell_size / dir[axis];
+            }
+        }
+
+        loop {
+            let axis = (0..3).fold(
+                0,
+                |best, axis| if next[axis] < next[best] { axis } else { best },
+            );
+            let exit = next[axis].min(far);
+            if let Some(indices) = self.cells.get(&(cell[0], cell[1], cell[2])) {
+                if visit(indices, exit) {
+                    return;
+                }
+            }
+            if exit >= far {
+                return;
+            }
+            cell[axis] += step[axis];
+            next[axis] += delta[axis];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::cube_at;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn scene(count: usize, rng: &mut StdRng) -> Vec<Object3d> {
+        (1..=count as u32)
+            .map(|id| {
+                let mut obj = cube_at(id, Vector3::zeros());
+                obj.position = Vector3::new(
+                    rng.gen_range(-15.0..15.0),
+                    rng.gen_range(-15.0..15.0),
+                    rng.gen_range(-15.0..15.0),
+                );
+                obj.rotation = rng.gen_range(0.0..std::f32::consts::TAU);
+                obj
+            })
+            .collect()
+    }
+
+    fn shown_bounds(objects: &[Object3d]) -> Vec<Option<Bounds>> {
+        objects
+            .iter()
+            .map(|obj| obj.render.then(|| obj.bounds()))
+            .collect()
+    }
+
+    fn brute_pairs(objects: &[Object3d]) -> Vec<(usize, usize)> {
+        let bounds = shown_bounds(objects);
+        let mut pairs = Vec::new();
+        for i in 0..bounds.len() {
+            for j in i + 1..bounds.len() {
+                if let (Some(a), Some(b)) = (&bounds[i], &bounds[j]) {
+                    if a.overlaps(b) {
+                        pairs.push((i, j));
+                    }
+                }
+            }
+        }
+        pairs
+    }
+
+    fn brute_query(objects: &[Object3d], bounds: &Bounds) -> Vec<usize> {
+        let shown = shown_bounds(objects);
+        (0..shown.len())
+            .filter(|&i| shown[i].is_some_and(|object_bounds| object_bounds.overlaps(bounds)))
+            .collect()
+    }
+
+    fn random_bounds(rng: &mut StdRng) -> Bounds {
+        let min = Vector3::new(
+            rng.gen_range(-20.0..20.0),
+            rng.gen_range(-20.0..20.0),
+            rng.gen_range(-20.0..20.0),
+        );
+        Bounds {
+            min,
+            max: min + Vector3::new(rng.gen_range(0.0..8.0), rng.gen_range(0.0..8.0), 3.0),
+        }
+    }
+
+    #[test]
+    fn pairs_and_queries_match_testing_everything() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let objects = scene(200, &mut rng);
+        for cell_size in [0.5, 3.0, 40.0] {
+            let mut grid = SpatialGrid::new(cell_size);
+            grid.sync(&objects);
+            assert_eq!(grid.len(), objects.len());
+            let pairs = brute_pairs(&objects);
+            assert!(!pairs.is_empty());
+            assert_eq!(grid.pairs(), pairs, "cell size {}", cell_size);
+            for _ in 0..50 {
+                let bounds = random_bounds(&mut rng);
+                assert_eq!(grid.query_bounds(&bounds), brute_query(&objects, &bounds));
+            }
+        }
+        // Bigger than every object together, it takes the sparse path
+        let everything = Bounds {
+            min: Vector3::new(-1000.0, -1000.0, -1000.0),
+            max: Vector3::new(1000.0, 1000.0, 1000.0),
+        };
+        let mut grid = SpatialGrid::new(3.0);
+        grid.sync(&objects);
+        assert_eq!(grid.query_bounds(&everything).len(), objects.len());
+    }
+
+    #[test]
+    fn sync_follows_moved_hidden_and_removed_objects() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut objects = scene(120, &mut rng);
+        let mut grid = SpatialGrid::new(3.0);
+        grid.sync(&objects);
+
+        for obj in objects.iter_mut().step_by(3) {
+            obj.position.x += rng.gen_range(-6.0..6.0);
+        }
+        for obj in objects.iter_mut().skip(1).step_by(7) {
+            obj.render = false;
+        }
+        objects.truncate(100);
+        grid.sync(&objects);
+
+        let shown = objects.iter().filter(|obj| obj.render).count();
+        assert_eq!(grid.len(), shown);
+        assert_eq!(grid.pairs(), brute_pairs(&objects));
+        for _ in 0..50 {
+            let bounds = random_bounds(&mut rng);
+            assert_eq!(grid.query_bounds(&bounds), brute_query(&objects, &bounds));
+        }
+
+        objects[0].position = Vector3::new(500.0, 0.0, 0.0);
+        grid.update_object(0, &objects[0]);
+        let far = Bounds {
+            min: Vector3::new(498.0, -2.0, -2.0),
+            max: Vector3::new(502.0, 2.0, 2.0),
+        };
+        assert_eq!(grid.query_bounds(&far), vec![0]);
+    }
+
+    #[test]
+    fn objects_over_too_many_cells_are_listed_apart() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let objects = scene(60, &mut rng);
+        // Turned cubes cover more cells than the cap, square ones fit under it
+        let mut grid = SpatialGrid::new(0.14);
+        grid.sync(&objects);
+        assert!(!grid.oversized.is_empty() && grid.oversized.len() < objects.len());
+        assert_eq!(grid.len(), objects.len());
+        assert_eq!(grid.pairs(), brute_pairs(&objects));
+        for _ in 0..50 {
+            let bounds = random_bounds(&mut rng);
+            assert_eq!(grid.query_bounds(&bounds), brute_query(&objects, &bounds));
+        }
+
+        let origin = Vector3::new(-30.0, 0.5, 0.2);
+        let dir = Vector3::x();
+        let mut visited = Vec::new();
+        grid.traverse_ray(&origin, &dir, 60.0, |indices, _| {
+            visited.extend_from_slice(indices);
+            false
+        });
+        for (i, obj) in objects.iter().enumerate() {
+            if obj.bounds().ray_interval(&origin, &dir, 60.0).is_some() {
+                assert!(visited.contains(&i), "object {} on the ray was missed", i);
+            }
+        }
+
+        // A box reaching the limits of the cell coordinates does not overflow the count
+        let huge = Bounds {
+            min: Vector3::repeat(-1e30),
+            max: Vector3::repeat(1e30),
+        };
+        assert_eq!(grid.query_bounds(&huge).len(), objects.len());
+    }
+
+    #[test]
+    fn rays_visit_cells_in_order_and_reach_every_object_they_cross() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let objects = scene(150, &mut rng);
+        let mut grid = SpatialGrid::new(2.5);
+        grid.sync(&objects);
+
+        for _ in 0..50 {
+            let origin = Vector3::new(
+                rng.gen_range(-25.0..25.0),
+                rng.gen_range(-25.0..25.0),
+                rng.gen_range(-25.0..25.0),
+            );
+            let dir = Vector3::new(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+            )
+            .normalize();
+            let max_distance = 40.0;
+
+            let mut visited = Vec::new();
+            let mut last_exit = 0.0;
+            grid.traverse_ray(&origin, &dir, max_distance, |indices, exit| {
+                assert!(exit >= last_exit && exit <= max_distance);
+                last_exit = exit;
+                visited.extend_from_slice(indices);
+                false
+            });
+            for (i, obj) in objects.iter().enumerate() {
+                if obj
+                    .bounds()
+                    .ray_interval(&origin, &dir, max_distance)
+                    .is_some()
+                {
+                    assert!(visited.contains(&i), "object {} on the ray was missed", i);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rays_without_a_valid_length_visit_nothing() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let objects = scene(50, &mut rng);
+        let mut grid = SpatialGrid::new(2.5);
+        grid.sync(&objects);
+
+        let origin = Vector3::new(-30.0, 0.0, 0.0);
+        for max_distance in [f32::NAN, -1.0, f32::NEG_INFINITY] {
+            let mut visits = 0;
+            grid.traverse_ray(&origin, &Vector3::x(), max_distance, |_, _| {
+                visits += 1;
+                false
+            });
+            assert_eq!(visits, 0, "max distance {}", max_distance);
+        }
+
+        let mut visits = 0;
+        grid.traverse_ray(&origin, &Vector3::x(), f32::INFINITY, |_, _| {
+            visits += 1;
+            false
+        });
+        assert!(visits > 0);
+    }
+}
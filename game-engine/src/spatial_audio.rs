@@ -0,0 +1,302 @@
+use na::Vector3;
+use rodio::Source;
+use std::collections::HashMap;
+use std::f32::consts::FRAC_PI_4;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How the volume decreases with the distance to the listener, like the OpenAL clamped models.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Falloff {
+    None,
+    Linear,
+    Inverse,
+    Exponential,
+}
+
+impl Falloff {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Falloff::None),
+            "linear" => Some(Falloff::Linear),
+            "inverse" => Some(Falloff::Inverse),
+            "exponential" => Some(Falloff::Exponential),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Attenuation {
+    pub falloff: Falloff,
+    // Closer than this the sound plays at full volume
+    pub min_distance: f32,
+    // Farther than this the volume stops changing (and is 0 with the linear model)
+    pub max_distance: f32,
+    pub rolloff: f32,
+}
+
+impl Default for Attenuation {
+    fn default() -> Self {
+        Attenuation {
+            falloff: Falloff::Inverse,
+            min_distance: 1.0,
+            max_distance: 100.0,
+            rolloff: 1.0,
+        }
+    }
+}
+
+impl Attenuation {
+    pub fn gain(&self, distance: f32) -> f32 {
+        let min = self.min_distance.max(0.001);
+        let max = self.max_distance.max(min);
+        let distance = distance.clamp(min, max);
+        let gain = match self.falloff {
+            Falloff::None => 1.0,
+            Falloff::Linear => {
+                if max > min {
+                    1.0 - self.rolloff * (distance - min) / (max - min)
+                } else {
+                    1.0
+                }
+            }
+            Falloff::Inverse => min / (min + self.rolloff * (distance - min)),
+            Falloff::Exponential => (distance / min).powf(-self.rolloff),
+        };
+        gain.clamp(0.0, 1.0)
+    }
+}
+
+/// The ears of the scene, it follows the camera.
+#[derive(Clone, Copy, Debug)]
+pub struct Listener {
+    pub position: Vector3<f32>,
+    // Unit vector pointing to the right ear
+    pub right: Vector3<f32>,
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Listener {
+            position: Vector3::zeros(),
+            right: Vector3::x(),
+        }
+    }
+}
+
+/// Where a positional sound comes from.
+#[derive(Clone, Copy, Debug)]
+pub enum Emitter {
+    Point(Vector3<f32>),
+    // The random_id of an Object3d, the sound follows it while it exists
+    Object(u32),
+}
+
+/// Per voice state shared between the game loop and the audio thread.
+pub struct SpatialState {
+    emitter: Mutex<Emitter>,
+    last_position: Mutex<Option<Vector3<f32>>>,
+    attenuation: Attenuation,
+    // f32 bits of the gains, written by the game loop and read by the audio thread
+    left_gain: AtomicU32,
+    right_gain: AtomicU32,
+}
+
+impl SpatialState {
+    pub fn new(emitter: Emitter, attenuation: Attenuation) -> Self {
+        SpatialState {
+            emitter: Mutex::new(emitter),
+            last_position: Mutex::new(None),
+            attenuation,
+            left_gain: AtomicU32::new(0),
+            right_gain: AtomicU32::new(0),
+        }
+    }
+
+    pub fn set_emitter(&self, emitter: Emitter) {
+        if let Ok(mut current) = self.emitter.lock() {
+            *current = emitter;
+        }
+    }
+
+    /// Recomputes the panning and distance attenuation from the current positions.
+    /// Objects that no longer exist keep their last known position.
+    pub fn update(&self, listener: &Listener, object_positions: &HashMap<u32, Vector3<f32>>) {
+        let Ok(emitter) = self.emitter.lock() else {
+            return;
+        };
+        let Ok(mut last_position) = self.last_position.lock() else {
+            return;
+        };
+        let position = match *emitter {
+            Emitter::Point(position) => position,
+            Emitter::Object(id) => match object_positions.get(&id).or(last_position.as_ref()) {
+                Some(position) => *position,
+                None => return,
+            },
+        };
+        *last_position = Some(position);
+
+        let offset = position - listener.position;
+        let distance = offset.norm();
+        let pan = if distance > 0.0001 {
+            (offset / distance).dot(&listener.right).clamp(-1.0, 1.0)
+        } else {
+            0.0
+        };
+        // Equal power panning, pan -1 is fully left and 1 fully right
+        let angle = (pan + 1.0) * FRAC_PI_4;
+        let gain = self.attenuation.gain(distance);
+        self.left_gain
+            .store((angle.cos() * gain).to_bits(), Ordering::Relaxed);
+        self.right_gain
+            .store((angle.sin() * gain).to_bits(), Ordering::Relaxed);
+    }
+
+    fn gains(&self) -> (f32, f32) {
+        (
+            f32::from_bits(self.left_gain.load(Ordering::Relaxed)),
+            f32::from_bits(self.right_gain.load(Ordering::Relaxed)),
+        )
+    }
+}
+
+/// Mixes the input down to mono and plays it in stereo with the gains of a `SpatialState`.
+pub struct Panned<I> {
+    input: I,
+    state: Arc<SpatialState>,
+    // Right sample of the current frame, waiting to be returned
+    pending_right: Option<f32>,
+}
+
+impl<I> Panned<I>
+where
+    I: Source<Item = f32>,
+{
+    pub fn new(input: I, state: Arc<SpatialState>) -> Self {
+        Panned {
+            input,
+            state,
+            pending_right: None,
+        }
+    }
+}
+
+impl<I> Iterator for Panned<I>
+where
+    I: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.pending_right.take() {
+            return Some(right);
+        }
+
+        let channels = self.input.channels().max(1);
+        let mut sum = self.input.next()?;
+        for _ in 1..channels {
+            sum += self.input.next().unwrap_or(0.0);
+        }
+        let mono = sum / channels as f32;
+
+        let (left_gain, right_gain) = self.state.gains();
+        self.pending_right = Some(mono * right_gain);
+        Some(mono * left_gain)
+    }
+}
+
+impl<I> Source for Panned<I>
+where
+    I: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        let channels = self.input.channels().max(1) as usize;
+        self.input.current_frame_len().map(|len| len / channels * 2)
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attenuation(falloff: Falloff) -> Attenuation {
+        Attenuation {
+            falloff,
+            min_distance: 2.0,
+            max_distance: 10.0,
+            rolloff: 1.0,
+        }
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-5,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn full_volume_inside_the_min_distance() {
+        for falloff in [
+            Falloff::None,
+            Falloff::Linear,
+            Falloff::Inverse,
+            Falloff::Exponential,
+        ] {
+            let attenuation = attenuation(falloff);
+            assert_close(attenuation.gain(0.0), 1.0);
+            assert_close(attenuation.gain(2.0), 1.0);
+        }
+        assert_close(attenuation(Falloff::None).gain(1000.0), 1.0);
+    }
+
+    #[test]
+    fn falloff_stops_at_the_max_distance() {
+        let linear = attenuation(Falloff::Linear);
+        assert_close(linear.gain(6.0), 0.5);
+        assert_close(linear.gain(10.0), 0.0);
+        assert_close(linear.gain(50.0), 0.0);
+
+        let inverse = attenuation(Falloff::Inverse);
+        assert_close(inverse.gain(4.0), 0.5);
+        assert_close(inverse.gain(10.0), 0.2);
+        assert_close(inverse.gain(50.0), 0.2);
+
+        let exponential = attenuation(Falloff::Exponential);
+        assert_close(exponential.gain(4.0), 0.5);
+        assert_close(exponential.gain(50.0), exponential.gain(10.0));
+    }
+
+    #[test]
+    fn gains_stay_between_zero_and_one() {
+        let steep = Attenuation {
+            rolloff: 3.0,
+            ..attenuation(Falloff::Linear)
+        };
+        assert_close(steep.gain(8.0), 0.0);
+        // A max distance under the min one plays at full volume everywhere
+        let inverted = Attenuation {
+            min_distance: 5.0,
+            max_distance: 1.0,
+            ..attenuation(Falloff::Inverse)
+        };
+        assert_close(inverted.gain(100.0), 1.0);
+    }
+}
@@ -0,0 +1,349 @@
+use rodio::Source;
+use std::f32::consts::PI;
+use std::time::Duration;
+
+const SAMPLE_RATE: u32 = 44100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Square,
+    Saw,
+    Triangle,
+    Noise,
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sine" => Some(Waveform::Sine),
+            "square" => Some(Waveform::Square),
+            "saw" => Some(Waveform::Saw),
+            "triangle" => Some(Waveform::Triangle),
+            "noise" => Some(Waveform::Noise),
+            _ => None,
+        }
+    }
+}
+
+/// Attack, decay, sustain, release envelope. Times are in seconds and `sustain` is a level.
+#[derive(Clone, Copy, Debug)]
+pub struct Envelope {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+impl Default for Envelope {
+    // Short attack and release so tones do not click
+    fn default() -> Self {
+        Envelope {
+            attack: 0.005,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.05,
+        }
+    }
+}
+
+impl Envelope {
+    /// Level of a note held during `duration` seconds, at `t` seconds from its start.
+    pub fn level(&self, t: f32, duration: f32) -> f32 {
+        if t >= duration {
+            let released = self.held_level(duration);
+            if self.release <= 0.0 {
+                return 0.0;
+            }
+            return (released * (1.0 - (t - duration) / self.release)).max(0.0);
+        }
+        self.held_level(t)
+    }
+
+    fn held_level(&self, t: f32) -> f32 {
+        if t < self.attack {
+            return t / self.attack;
+        }
+        let t = t - self.attack;
+        if t < self.decay {
+            return 1.0 - (1.0 - self.sustain) * t / self.decay;
+        }
+        self.sustain
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    None,
+    // Cutoff frequency in Hz of a one pole filter
+    LowPass(f32),
+    HighPass(f32),
+}
+
+/// Description of a synthesized sound, like `play_tone{wave='square', freq=440, duration=0.2}`.
+#[derive(Clone, Copy, Debug)]
+pub struct ToneSpec {
+    pub wave: Waveform,
+    pub freq: f32,
+    // The frequency slides linearly to this one over the whole sound, for lasers and jumps
+    pub freq_end: Option<f32>,
+    // Seconds the note is held, the release of the envelope is played after it
+    pub duration: f32,
+    // Fraction of the period the square wave is high
+    pub duty: f32,
+    pub envelope: Envelope,
+    pub filter: Filter,
+    // Seed of the noise generator, so the same spec always sounds the same
+    pub seed: u32,
+}
+
+impl Default for ToneSpec {
+    fn default() -> Self {
+        ToneSpec {
+            wave: Waveform::Sine,
+            freq: 440.0,
+            freq_end: None,
+            duration: 0.2,
+            duty: 0.5,
+            envelope: Envelope::default(),
+            filter: Filter::None,
+            seed: 1,
+        }
+    }
+}
+
+/// A mono rodio source that generates a `ToneSpec` sample by sample.
+pub struct Tone {
+    spec: ToneSpec,
+    sample: u64,
+    total_samples: u64,
+    phase: f32,
+    noise_state: u32,
+    filter_input: f32,
+    filter_output: f32,
+}
+
+impl Tone {
+    pub fn new(spec: ToneSpec) -> Self {
+        let total = spec.duration.max(0.0) + spec.envelope.release.max(0.0);
+        Tone {
+            spec,
+            sample: 0,
+            total_samples: (total * SAMPLE_RATE as f32) as u64,
+            phase: 0.0,
+            noise_state: spec.seed.max(1),
+            filter_input: 0.0,
+            filter_output: 0.0,
+        }
+    }
+
+    // Xorshift, cheap and deterministic
+    fn next_noise(&mut self) -> f32 {
+        let mut x = self.noise_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise_state = x;
+        (x as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+
+    fn oscillator(&mut self) -> f32 {
+        let phase = self.phase;
+        match self.spec.wave {
+            Waveform::Sine => (phase * 2.0 * PI).sin(),
+            Waveform::Square => {
+                if phase < self.spec.duty {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Saw => phase * 2.0 - 1.0,
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Noise => self.next_noise(),
+        }
+    }
+
+    fn filter(&mut self, input: f32) -> f32 {
+        let dt = 1.0 / SAMPLE_RATE as f32;
+        match self.spec.filter {
+            Filter::None => input,
+            Filter::LowPass(cutoff) => {
+                let rc = 1.0 / (2.0 * PI * cutoff.max(1.0));
+                let alpha = dt / (rc + dt);
+                self.filter_output += alpha * (input - self.filter_output);
+                self.filter_output
+            }
+            Filter::HighPass(cutoff) => {
+                let rc = 1.0 / (2.0 * PI * cutoff.max(1.0));
+                let alpha = rc / (rc + dt);
+                self.filter_output = alpha * (self.filter_output + input - self.filter_input);
+                self.filter_input = input;
+                self.filter_output
+            }
+        }
+    }
+}
+
+impl Iterator for Tone {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.sample >= self.total_samples {
+            return None;
+        }
+        let t = self.sample as f32 / SAMPLE_RATE as f32;
+        let progress = self.sample as f32 / self.total_samples as f32;
+        self.sample += 1;
+
+        let raw = self.oscillator();
+        let value = self.filter(raw) * self.spec.envelope.level(t, self.spec.duration);
+
+        let freq = match self.spec.freq_end {
+            Some(freq_end) => self.spec.freq + (freq_end - self.spec.freq) * progress,
+            None => self.spec.freq,
+        };
+        self.phase = (self.phase + freq / SAMPLE_RATE as f32).rem_euclid(1.0);
+        Some(value)
+    }
+}
+
+impl Source for Tone {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(Duration::from_secs_f64(
+            self.total_samples as f64 / SAMPLE_RATE as f64,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Full volume from the first sample to the last one
+    const FLAT: Envelope = Envelope {
+        attack: 0.0,
+        decay: 0.0,
+        sustain: 1.0,
+        release: 0.0,
+    };
+
+    fn samples(wave: Waveform, freq: f32, filter: Filter, count: usize) -> Vec<f32> {
+        Tone::new(ToneSpec {
+            wave,
+            freq,
+            duration: 1.0,
+            envelope: FLAT,
+            filter,
+            ..ToneSpec::default()
+        })
+        .take(count)
+        .collect()
+    }
+
+    fn assert_samples(found: &[f32], expected: &[f32]) {
+        assert_eq!(found.len(), expected.len());
+        for (found, expected) in found.iter().zip(expected) {
+            assert!(
+                (found - expected).abs() < 1e-4,
+                "{:?} != {:?}",
+                found,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn waveforms_at_each_quarter_of_the_period() {
+        // A quarter of the period per sample
+        let freq = SAMPLE_RATE as f32 / 4.0;
+        let wave = |wave| samples(wave, freq, Filter::None, 5);
+        assert_samples(&wave(Waveform::Sine), &[0.0, 1.0, 0.0, -1.0, 0.0]);
+        assert_samples(&wave(Waveform::Square), &[1.0, 1.0, -1.0, -1.0, 1.0]);
+        assert_samples(&wave(Waveform::Saw), &[-1.0, -0.5, 0.0, 0.5, -1.0]);
+        assert_samples(&wave(Waveform::Triangle), &[-1.0, 0.0, 1.0, 0.0, -1.0]);
+
+        let noise = wave(Waveform::Noise);
+        assert_eq!(noise, wave(Waveform::Noise));
+        assert!(noise.iter().all(|sample| (-1.0..=1.0).contains(sample)));
+    }
+
+    #[test]
+    fn envelope_levels_of_each_stage() {
+        let envelope = Envelope {
+            attack: 0.1,
+            decay: 0.2,
+            sustain: 0.5,
+            release: 0.4,
+        };
+        let levels =
+            [0.0, 0.05, 0.1, 0.2, 0.3, 0.6, 1.0, 1.2, 1.4, 2.0].map(|t| envelope.level(t, 1.0));
+        assert_samples(
+            &levels,
+            &[0.0, 0.5, 1.0, 0.75, 0.5, 0.5, 0.5, 0.25, 0.0, 0.0],
+        );
+        // Released during the attack, it fades from where it got to
+        assert_samples(&[envelope.level(0.25, 0.05)], &[0.25]);
+        let no_release = Envelope {
+            release: 0.0,
+            ..envelope
+        };
+        assert_eq!(no_release.level(1.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn tones_last_their_duration_and_release() {
+        let spec = ToneSpec {
+            duration: 0.5,
+            envelope: Envelope {
+                release: 0.25,
+                ..Envelope::default()
+            },
+            ..ToneSpec::default()
+        };
+        let tone = Tone::new(spec);
+        assert_eq!(tone.total_duration(), Some(Duration::from_secs_f64(0.75)));
+        let samples: Vec<f32> = tone.collect();
+        assert_eq!(samples.len(), 33075);
+        assert_eq!(samples[0], 0.0);
+    }
+
+    #[test]
+    fn filters_keep_or_remove_dc_and_nyquist() {
+        let amplitude = |samples: &[f32]| samples.iter().fold(0.0f32, |max, s| max.max(s.abs()));
+        // A square wave always high is a constant
+        let dc = |filter| {
+            let spec = ToneSpec {
+                wave: Waveform::Square,
+                duty: 1.0,
+                duration: 1.0,
+                envelope: FLAT,
+                filter,
+                ..ToneSpec::default()
+            };
+            Tone::new(spec).skip(10_000).take(100).collect::<Vec<f32>>()
+        };
+        // Two samples per period, the highest frequency there is
+        let nyquist = |filter| {
+            samples(Waveform::Square, SAMPLE_RATE as f32 / 2.0, filter, 10_100)[10_000..].to_vec()
+        };
+
+        assert_samples(&dc(Filter::LowPass(200.0)), &[1.0; 100]);
+        assert!(amplitude(&nyquist(Filter::LowPass(200.0))) < 0.02);
+        assert!(amplitude(&dc(Filter::HighPass(200.0))) < 1e-3);
+        assert!(amplitude(&nyquist(Filter::HighPass(200.0))) > 0.95);
+        assert_samples(&nyquist(Filter::None)[..2], &[1.0, -1.0]);
+    }
+}
@@ -0,0 +1,12 @@
+//! Fixtures shared by the unit tests.
+use na::Vector3;
+
+use crate::object3d::Object3d;
+
+/// A cube of side 2 centered on its origin.
+pub const CUBE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/model/cube.obj");
+
+/// The cube model at `position`, without rotation and with the given id.
+pub fn cube_at(id: u32, position: Vector3<f32>) -> Object3d {
+    Object3d::new(id, CUBE, position, 0.0)
+}
@@ -0,0 +1,69 @@
+/// Splits the variable frame time into simulation steps of a fixed length.
+pub struct FixedTimestep {
+    // Seconds per step
+    pub step: f32,
+    pub max_frame_time: f32,
+    accumulator: f32,
+}
+
+impl FixedTimestep {
+    /// `hz` must be positive and `max_frame_time` at least one step long, `GameConfig::load`
+    /// checks both.
+    pub fn new(hz: f32, max_frame_time: f32) -> Self {
+        FixedTimestep {
+            step: 1.0 / hz,
+            max_frame_time,
+            accumulator: 0.0,
+        }
+    }
+
+    /// Adds the time of a frame and returns how many steps to simulate.
+    pub fn advance(&mut self, frame_time: f32) -> u32 {
+        self.accumulator += frame_time.clamp(0.0, self.max_frame_time);
+        let steps = (self.accumulator / self.step) as u32;
+        self.accumulator -= steps as f32 * self.step;
+        steps
+    }
+
+    /// How far the time is between the last step and the next one, from 0 to 1.
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / self.step).clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_frames_catch_up_with_several_steps() {
+        let mut timestep = FixedTimestep::new(8.0, 1.0);
+        assert_eq!(timestep.advance(0.0625), 0);
+        assert_eq!(timestep.advance(0.0625), 1);
+        assert_eq!(timestep.advance(0.375), 3);
+        assert_eq!(timestep.advance(-1.0), 0);
+    }
+
+    #[test]
+    fn frame_time_is_clamped_to_avoid_the_spiral_of_death() {
+        let mut timestep = FixedTimestep::new(8.0, 0.3125);
+        assert_eq!(timestep.advance(5.0), 2);
+        // Only the half step left of the clamped frame carries over
+        assert_eq!(timestep.alpha(), 0.5);
+        assert_eq!(timestep.advance(0.0625), 1);
+    }
+
+    #[test]
+    fn alpha_stays_between_zero_and_one() {
+        let mut timestep = FixedTimestep::new(60.0, 0.25);
+        assert_eq!(timestep.alpha(), 0.0);
+        for frame_time in [0.001, 0.007, 0.016, 0.1, 0.25, 3.0] {
+            timestep.advance(frame_time);
+            let alpha = timestep.alpha();
+            assert!((0.0..=1.0).contains(&alpha), "alpha {}", alpha);
+        }
+        let mut timestep = FixedTimestep::new(8.0, 1.0);
+        timestep.advance(0.03125);
+        assert_eq!(timestep.alpha(), 0.25);
+    }
+}